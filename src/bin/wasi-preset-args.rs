@@ -1,5 +1,8 @@
-use clap::Parser;
-use std::{ffi::OsString, path::PathBuf};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
 
 #[derive(Parser)]
 pub struct Opt {
@@ -16,13 +19,205 @@ pub struct Opt {
     #[clap(short, long)]
     program_name: Option<OsString>,
 
-    /// Arguments to preset for the program
+    /// How argv[0] is decided: "keep" (use the runtime's one as is), "fallback" (use the
+    /// program name only when the runtime doesn't provide it), "override" (always use the
+    /// program name) or "basename" (use the basename of the runtime's one).
+    #[clap(long, default_value = "fallback")]
+    argv0: wasi_preset_args::Argv0Policy,
+
+    /// Where to place the preset arguments relative to the arguments given
+    /// by the runtime: "prepend", "append", "after:N" (after the first N arguments) or
+    /// "template" (where the "$@" in ARGS is).
+    #[clap(long, default_value = "prepend")]
+    placement: wasi_preset_args::Placement,
+
+    /// What to do with "--placement after:N" when the runtime gives fewer than N
+    /// arguments: "append" (place them at the end), "skip" (don't add them) or "error".
+    #[clap(long, default_value = "append")]
+    fewer_args_policy: wasi_preset_args::FewerArgsPolicy,
+
+    /// Insert the separator between the preset arguments and the arguments given by the
+    /// runtime following them, e.g. "--separator=--". Not allowed with "--placement template".
+    #[clap(long, value_name = "SEP", allow_hyphen_values = true)]
+    separator: Option<OsString>,
+
+    /// Preset the arguments only when the runtime gives no arguments except argv[0].
+    #[clap(long)]
+    default_only: bool,
+
+    /// Read a table of the preset arguments selected by the basename of argv[0], for
+    /// multicall programs. Each line of the file is a name followed by the arguments,
+    /// separated by whitespace. Empty lines and lines starting with "#" are ignored.
+    /// The escapes of --args-file are supported, and "\ " is an escape for a space.
+    /// The ARGS are used when no name matches.
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    multicall: Option<PathBuf>,
+
+    /// Expand "${NAME}" in the preset arguments with the environment variables when
+    /// the program runs. "$${" is an escape for a literal "${".
+    #[clap(long)]
+    expand_env: bool,
+
+    /// With --expand-env, what undefined variables are expanded to: "empty" or "keep"
+    /// (leave "${NAME}" as is).
+    #[clap(long, default_value = "empty", requires = "expand-env")]
+    undefined_env: wasi_preset_args::UndefinedEnvPolicy,
+
+    /// Drop all arguments given by the runtime except argv[0].
+    #[clap(long)]
+    sealed: bool,
+
+    /// With --sealed, also replace argv[0] given by the runtime with the program name.
+    #[clap(long, requires = "sealed")]
+    replace_program_name: bool,
+
+    /// Drop the arguments given by the runtime that match the pattern. A trailing "*"
+    /// matches any arguments starting with the rest, e.g. "--unsafe-*". Can be repeated.
+    #[clap(long = "deny-arg", value_name = "PATTERN", allow_hyphen_values = true)]
+    deny_args: Vec<wasi_preset_args::ArgPattern>,
+
+    /// Drop the preset arguments with the key ("--key=value" or "--key") when the runtime
+    /// gives an argument with the same key. Can be repeated up to 32 times.
+    #[clap(long, value_name = "KEY", allow_hyphen_values = true)]
+    overridable: Vec<OsString>,
+
+    /// Fail when the runtime gives more than N arguments (except argv[0] and the denied ones).
+    #[clap(long, value_name = "N")]
+    max_runtime_args: Option<u32>,
+
+    /// Fail when the total size of the arguments, including the preset ones and the program
+    /// name, exceeds BYTES.
+    #[clap(long, value_name = "BYTES")]
+    max_argv_buf_size: Option<u32>,
+
+    /// WASI errno returned to the program when a limit is exceeded or the size of
+    /// the arguments overflows. Defaults to __WASI_ERRNO_2BIG.
+    #[clap(long, default_value = "1")]
+    limit_errno: u16,
+
+    /// Read the arguments to preset from the file, one per line, before ARGS. Empty lines and
+    /// lines starting with "#" are ignored. "\\", "\n", "\t", "\r", "\#" and "\ " are escapes.
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    args_file: Option<PathBuf>,
+
+    /// Read the environment variables to preset from the dotenv file, before --env.
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    env_file: Option<PathBuf>,
+
+    /// Preset the environment variable for the program. Can be repeated.
+    #[clap(long = "env", value_name = "KEY=VALUE", parse(try_from_str = parse_env_var))]
+    envs: Vec<(OsString, OsString)>,
+
+    /// What to do when the runtime also gives the environment variable preset by --env:
+    /// "preset" (the runtime one is dropped), "host" (the preset one is dropped) or "error".
+    /// Can be repeated for each variable. At most 32 variables can have "host".
+    #[clap(
+        long,
+        value_name = "KEY=PRECEDENCE",
+        parse(try_from_str = parse_env_precedence)
+    )]
+    env_precedence: Vec<(OsString, wasi_preset_args::EnvPrecedence)>,
+
+    /// Pass only the environment variables given by the runtime with the keys matching any of
+    /// the patterns. A trailing "*" matches any keys starting with the rest, e.g. "LC_*".
+    /// Can be repeated.
+    #[clap(long = "allow-host-env", value_name = "PATTERN")]
+    allowed_host_envs: Vec<wasi_preset_args::ArgPattern>,
+
+    /// Drop the environment variables given by the runtime with the keys matching the pattern,
+    /// e.g. "AWS_*". Can be repeated.
+    #[clap(long = "deny-host-env", value_name = "PATTERN")]
+    denied_host_envs: Vec<wasi_preset_args::ArgPattern>,
+
+    /// Report the preopened directory named FROM by the runtime as TO, e.g. "--rename-preopen
+    /// /data=/mnt/data". Can be repeated.
+    #[clap(long, value_name = "FROM=TO", parse(try_from_str = parse_rename))]
+    rename_preopen: Vec<(OsString, OsString)>,
+
+    /// Hide the preopened directories from the program except the ones named NAME by the
+    /// runtime. Can be repeated.
+    #[clap(long, value_name = "NAME")]
+    allow_preopen: Vec<OsString>,
+
+    /// Make the clock return TIME nanoseconds, advancing by STEP nanoseconds on each read if
+    /// given, e.g. "realtime=1600000000000000000+1000000". CLOCK is one of realtime,
+    /// monotonic, process_cputime and thread_cputime. Can be repeated.
+    #[clap(long, value_name = "CLOCK=TIME[+STEP]", parse(try_from_str = parse_frozen_clock))]
+    freeze_clock: Vec<(wasi_preset_args::ClockId, u64, u64)>,
+
+    /// Replace the randomness given by the runtime with a deterministic one generated from
+    /// SEED.
+    #[clap(long, value_name = "SEED")]
+    random_seed: Option<u64>,
+
+    /// Mix the value of the environment variable NAME given by the runtime into the seed of
+    /// --random-seed.
+    #[clap(long, value_name = "NAME", requires = "random-seed")]
+    random_seed_env: Option<OsString>,
+
+    /// Embed the content of the file in the module and serve it as the stdin of the program.
+    #[clap(long, value_name = "FILE")]
+    stdin_file: Option<PathBuf>,
+
+    /// Read the stdin given by the runtime after the content of --stdin-file instead of
+    /// reaching EOF.
+    #[clap(long, requires = "stdin-file")]
+    read_host_stdin: bool,
+
+    /// Make the program exit with TO instead of FROM, e.g. "1=0". FROM is an exit code or
+    /// "nonzero", which matches the nonzero exit codes not matched by the others. FROM can't
+    /// be 0, which wasi-libc doesn't pass when main returns. Can be repeated.
+    #[clap(long, value_name = "FROM=TO", parse(try_from_str = parse_exit_code_mapping))]
+    map_exit_code: Vec<(wasi_preset_args::ExitCodePattern, u32)>,
+
+    /// Embed the file as a read-only file at PATH under --embed-dir, e.g. "--embed-file
+    /// app.toml=./config/app.toml". Can be repeated.
+    #[clap(long, value_name = "PATH=FILE", parse(try_from_str = parse_embedded_file))]
+    embed_file: Vec<(OsString, PathBuf)>,
+
+    /// The virtual directory seen by the program containing the files of --embed-file.
+    #[clap(long, value_name = "DIR", default_value = "/embedded")]
+    embed_dir: OsString,
+
+    /// Arguments to preset for the program. "@FILE" is replaced with the arguments read from
+    /// the file in the format of --args-file, and "@@" is an escape for a literal "@".
     #[clap(name = "ARGS", last = true)]
     args: Vec<OsString>,
 }
 
+/// Bundle a script with an interpreter module, e.g. python.wasm, into a standalone module.
+/// The script is embedded as a read-only file under --embed-dir, and argv is preset to
+/// [interp, <path of the script>, ARGS...].
+#[derive(Parser)]
+pub struct BundleOpt {
+    /// The script to embed.
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    script: PathBuf,
+
+    #[clap(flatten)]
+    opt: Opt,
+}
+
 fn main() -> anyhow::Result<()> {
-    let opt = Opt::parse();
+    let matches = Opt::command()
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(BundleOpt::command().name("bundle"))
+        .get_matches();
+    match matches.subcommand() {
+        Some(("bundle", matches)) => {
+            let bundle_opt = BundleOpt::from_arg_matches(matches)?;
+            run(bundle_opt.opt, Some(bundle_opt.script))
+        }
+        _ => run(Opt::from_arg_matches(&matches)?, None),
+    }
+}
+
+fn run(opt: Opt, script: Option<PathBuf>) -> anyhow::Result<()> {
+    let bundle = match &script {
+        Some(script) => Some(bundle_script(&opt, script)?),
+        None => None,
+    };
     let mut module_config = walrus::ModuleConfig::new();
     module_config.strict_validate(false);
     let mut module = module_config.parse_file(&opt.file)?;
@@ -36,9 +231,480 @@ fn main() -> anyhow::Result<()> {
             .ok_or_else(|| anyhow::anyhow!("no file name in path: {:?}", opt.file))?;
         file_name.to_owned()
     };
-    let preset_args = wasi_preset_args::PresetArgs::new(program_name, opt.args);
+    let mut embedded_files = vec![];
+    for (path, file) in opt.embed_file {
+        embedded_files.push((path, read_embedded_file(&file)?));
+    }
+    let mut args = vec![];
+    if let Some((script_arg, script_file)) = bundle {
+        args.push(script_arg);
+        embedded_files.insert(0, script_file);
+    }
+    if let Some(path) = &opt.args_file {
+        args.extend(read_args_file(path)?);
+    }
+    args.extend(expand_response_files(opt.args)?);
+    let mut preset_args = wasi_preset_args::PresetArgs::new(program_name, args);
+    preset_args
+        .placement(opt.placement)
+        .fewer_args_policy(opt.fewer_args_policy)
+        .argv0_policy(opt.argv0)
+        .default_only(opt.default_only);
+    if opt.sealed {
+        preset_args.runtime_args(wasi_preset_args::RuntimeArgs::Sealed {
+            replace_program_name: opt.replace_program_name,
+        });
+    }
+    if let Some(path) = opt.multicall {
+        for (name, args) in read_multicall_table(&path)? {
+            preset_args.multicall(name, args);
+        }
+    }
+    if opt.expand_env {
+        preset_args.expand_env(opt.undefined_env);
+    }
+    if let Some(separator) = opt.separator {
+        if opt.placement == wasi_preset_args::Placement::Template {
+            anyhow::bail!("--separator can't be used with --placement template; put it before $@");
+        }
+        preset_args.separator(separator);
+    }
+    for key in opt.overridable {
+        preset_args.overridable(key);
+    }
+    for pattern in opt.deny_args {
+        preset_args.deny_runtime_arg(pattern);
+    }
+    if let Some(max) = opt.max_runtime_args {
+        preset_args.max_runtime_argc(max);
+    }
+    if let Some(max) = opt.max_argv_buf_size {
+        preset_args.max_argv_buf_size(max);
+    }
+    preset_args.limit_errno(opt.limit_errno);
     preset_args.run(&mut module)?;
+    let mut envs = match &opt.env_file {
+        Some(path) => read_env_file(path)?,
+        None => vec![],
+    };
+    envs.extend(opt.envs);
+    if !envs.is_empty()
+        || !opt.env_precedence.is_empty()
+        || !opt.allowed_host_envs.is_empty()
+        || !opt.denied_host_envs.is_empty()
+    {
+        let mut preset_env = wasi_preset_args::PresetEnv::new(envs);
+        for (key, precedence) in opt.env_precedence {
+            preset_env.precedence(key, precedence)?;
+        }
+        for pattern in opt.allowed_host_envs {
+            preset_env.allow_host_var(pattern);
+        }
+        for pattern in opt.denied_host_envs {
+            preset_env.deny_host_var(pattern);
+        }
+        preset_env.run(&mut module)?;
+    }
+    if !embedded_files.is_empty() {
+        let mut preset_files = wasi_preset_args::PresetFiles::new(opt.embed_dir);
+        for (path, content) in embedded_files {
+            preset_files.file(path, content);
+        }
+        preset_files.run(&mut module)?;
+    }
+    if !opt.rename_preopen.is_empty() || !opt.allow_preopen.is_empty() {
+        let mut preset_preopens = wasi_preset_args::PresetPreopens::new();
+        for (from, to) in opt.rename_preopen {
+            preset_preopens.rename(from, to);
+        }
+        for name in opt.allow_preopen {
+            preset_preopens.allow(name);
+        }
+        preset_preopens.run(&mut module)?;
+    }
+    if !opt.freeze_clock.is_empty() {
+        let mut preset_clock = wasi_preset_args::PresetClock::new();
+        for (id, time, step) in opt.freeze_clock {
+            preset_clock.freeze(id, time, step);
+        }
+        preset_clock.run(&mut module)?;
+    }
+    if let Some(seed) = opt.random_seed {
+        let mut preset_random = wasi_preset_args::PresetRandom::new(seed);
+        if let Some(name) = opt.random_seed_env {
+            preset_random.seed_from_env(name);
+        }
+        preset_random.run(&mut module)?;
+    }
+    if let Some(path) = opt.stdin_file {
+        let mut preset_stdin = wasi_preset_args::PresetStdin::new(read_embedded_file(&path)?);
+        preset_stdin.read_host_stdin(opt.read_host_stdin);
+        preset_stdin.run(&mut module)?;
+    }
+    if !opt.map_exit_code.is_empty() {
+        let mut preset_exit = wasi_preset_args::PresetExit::new();
+        for (from, to) in opt.map_exit_code {
+            preset_exit.map(from, to);
+        }
+        preset_exit.run(&mut module)?;
+    }
 
     module.emit_wasm_file(opt.output)?;
     Ok(())
 }
+
+/// Return the preset arg and the embedded file for the script to bundle. The options that
+/// would move the script away from argv[1] or drop it are rejected, as the interpreter takes
+/// the first argument as the script.
+fn bundle_script(opt: &Opt, script: &Path) -> anyhow::Result<(OsString, (OsString, Vec<u8>))> {
+    if opt.placement != wasi_preset_args::Placement::Prepend {
+        anyhow::bail!("bundle places the script first; --placement can't be used");
+    }
+    if opt.default_only {
+        anyhow::bail!("bundle always runs the script; --default-only can't be used");
+    }
+    if opt.multicall.is_some() {
+        anyhow::bail!("bundle always runs the script; --multicall can't be used");
+    }
+    let file_name = script
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("no file name in path: {:?}", script))?;
+    // The interpreter opens the script by the path under the virtual directory.
+    let script_arg = Path::new(&opt.embed_dir).join(file_name);
+    let script_arg = script_arg.to_string_lossy();
+    let script_key = script_arg.split('=').next().unwrap_or_default();
+    if opt.overridable.iter().any(|key| key == script_key) {
+        anyhow::bail!("--overridable {} would drop the script", script_key);
+    }
+    // The path is not a template
+    let script_arg = if opt.expand_env {
+        script_arg.replace("${", "$${")
+    } else {
+        script_arg.into_owned()
+    };
+    let file = (file_name.to_owned(), read_embedded_file(script)?);
+    Ok((script_arg.into(), file))
+}
+
+fn read_embedded_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow::anyhow!("failed to read {:?}: {}", path, e))
+}
+
+fn read_file(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("failed to read {:?}: {}", path, e))
+}
+
+/// Read one arg per line. Empty lines and lines starting with "#" are skipped.
+fn read_args_file(path: &Path) -> anyhow::Result<Vec<OsString>> {
+    let mut args = vec![];
+    for (i, line) in read_file(path)?.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let arg = unescape_arg(line)
+            .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), i + 1, e))?;
+        args.push(arg.into());
+    }
+    Ok(args)
+}
+
+fn unescape_arg(line: &str) -> anyhow::Result<String> {
+    let mut arg = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            arg.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => arg.push('\\'),
+            Some('n') => arg.push('\n'),
+            Some('t') => arg.push('\t'),
+            Some('r') => arg.push('\r'),
+            Some('#') => arg.push('#'),
+            Some(' ') => arg.push(' '),
+            Some(c) => anyhow::bail!("unknown escape: \\{}", c),
+            None => anyhow::bail!("trailing backslash"),
+        }
+    }
+    Ok(arg)
+}
+
+/// Replace "@FILE" with the args read from the file.
+fn expand_response_files(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let mut expanded = vec![];
+    for arg in args {
+        match arg.to_str().and_then(|arg| arg.strip_prefix('@')) {
+            Some(rest) if rest.starts_with('@') => expanded.push(rest.into()),
+            Some(path) => expanded.extend(read_args_file(Path::new(path))?),
+            None => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Read `KEY=VALUE` lines in the dotenv format. Lines may start with "export ". Values can be
+/// quoted with '...' (as is) or "..." (with "\\", "\n", "\t", "\r" and "\"" escapes), and
+/// unquoted values end at " #".
+fn read_env_file(path: &Path) -> anyhow::Result<Vec<(OsString, OsString)>> {
+    let mut envs = vec![];
+    for (i, line) in read_file(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = parse_env_line(line)
+            .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), i + 1, e))?;
+        envs.push((key.into(), value.into()));
+    }
+    Ok(envs)
+}
+
+fn parse_env_line(line: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = match line.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => (key.trim(), value.trim_start()),
+        _ => anyhow::bail!("invalid line: {} (expected KEY=VALUE)", line),
+    };
+    let value = if let Some(rest) = value.strip_prefix('\'') {
+        match rest.split_once('\'') {
+            Some((value, _)) => value.to_string(),
+            None => anyhow::bail!("unterminated quote: {}", line),
+        }
+    } else if let Some(rest) = value.strip_prefix('"') {
+        let mut unquoted = String::new();
+        let mut chars = rest.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => unquoted.push('\n'),
+                    Some('t') => unquoted.push('\t'),
+                    Some('r') => unquoted.push('\r'),
+                    Some(c @ ('\\' | '"')) => unquoted.push(c),
+                    Some(c) => anyhow::bail!("unknown escape: \\{}", c),
+                    None => anyhow::bail!("unterminated quote: {}", line),
+                },
+                Some(c) => unquoted.push(c),
+                None => anyhow::bail!("unterminated quote: {}", line),
+            }
+        }
+        unquoted
+    } else {
+        let value = match value.find(" #") {
+            Some(i) => &value[..i],
+            None => value,
+        };
+        value.trim_end().to_string()
+    };
+    Ok((key.to_string(), value))
+}
+
+fn read_multicall_table(path: &Path) -> anyhow::Result<Vec<(OsString, Vec<OsString>)>> {
+    let contents = read_file(path)?;
+    let mut table = vec![];
+    for (i, line) in contents.lines().enumerate() {
+        // Not trimming the end, which may be an escaped space
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = split_escaped_words(line)
+            .into_iter()
+            .map(|word| unescape_arg(word).map(OsString::from))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), i + 1, e))?
+            .into_iter();
+        if let Some(name) = words.next() {
+            table.push((name, words.collect()));
+        }
+    }
+    Ok(table)
+}
+
+/// Split the line at the whitespace not escaped with "\", e.g. "a\ b c" into ["a\ b", "c"].
+fn split_escaped_words(line: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c.is_whitespace() {
+            if let Some(start) = start.take() {
+                words.push(&line[start..i]);
+            }
+        } else {
+            escaped = c == '\\';
+            start.get_or_insert(i);
+        }
+    }
+    if let Some(start) = start {
+        words.push(&line[start..]);
+    }
+    words
+}
+
+fn parse_env_var(s: &str) -> anyhow::Result<(OsString, OsString)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.into(), value.into())),
+        _ => anyhow::bail!("invalid environment variable: {} (expected KEY=VALUE)", s),
+    }
+}
+
+fn parse_rename(s: &str) -> anyhow::Result<(OsString, OsString)> {
+    match s.split_once('=') {
+        Some((from, to)) => Ok((from.into(), to.into())),
+        None => anyhow::bail!("invalid rename: {} (expected FROM=TO)", s),
+    }
+}
+
+fn parse_embedded_file(s: &str) -> anyhow::Result<(OsString, PathBuf)> {
+    match s.split_once('=') {
+        Some((path, file)) => Ok((path.into(), file.into())),
+        None => anyhow::bail!("invalid embedded file: {} (expected PATH=FILE)", s),
+    }
+}
+
+fn parse_frozen_clock(s: &str) -> anyhow::Result<(wasi_preset_args::ClockId, u64, u64)> {
+    let (id, time) = match s.split_once('=') {
+        Some(pair) => pair,
+        None => anyhow::bail!("invalid frozen clock: {} (expected CLOCK=TIME[+STEP])", s),
+    };
+    let (time, step) = time.split_once('+').unwrap_or((time, "0"));
+    Ok((id.parse()?, time.parse()?, step.parse()?))
+}
+
+fn parse_exit_code_mapping(s: &str) -> anyhow::Result<(wasi_preset_args::ExitCodePattern, u32)> {
+    let (from, to) = match s.split_once('=') {
+        Some((from, to)) => (from.parse()?, to.parse()?),
+        None => anyhow::bail!("invalid exit code mapping: {} (expected FROM=TO)", s),
+    };
+    // Returning 0 from main doesn't reach proc_exit, so the mapping would only apply to exit(0)
+    if from == wasi_preset_args::ExitCodePattern::Code(0) {
+        anyhow::bail!("invalid exit code mapping: {} (FROM can't be 0)", s);
+    }
+    Ok((from, to))
+}
+
+fn parse_env_precedence(s: &str) -> anyhow::Result<(OsString, wasi_preset_args::EnvPrecedence)> {
+    match s.split_once('=') {
+        Some((key, precedence)) => Ok((key.into(), precedence.parse()?)),
+        None => anyhow::bail!("invalid env precedence: {} (expected KEY=PRECEDENCE)", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `contents` to a file unique to the test and return its path.
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("wasi-preset-args-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn unescape_arg_escapes() {
+        assert_eq!(unescape_arg("--foo=bar").unwrap(), "--foo=bar");
+        assert_eq!(
+            unescape_arg(r"a\\b\nc\td\re\#f\ g").unwrap(),
+            "a\\b\nc\td\re#f g"
+        );
+        assert!(unescape_arg(r"\x").is_err());
+        assert!(unescape_arg(r"foo\").is_err());
+    }
+
+    #[test]
+    fn read_args_file_comments() {
+        let path = temp_file(
+            "args",
+            "# comment\n--foo\n\n\\# not a comment\n  indented\ntrailing\\ \n",
+        );
+        assert_eq!(
+            read_args_file(&path).unwrap(),
+            ["--foo", "# not a comment", "  indented", "trailing "]
+        );
+        let path = temp_file("args-error", "--foo\n--bar\\q\n");
+        let err = read_args_file(&path).unwrap_err().to_string();
+        assert!(err.ends_with(":2: unknown escape: \\q"), "{}", err);
+    }
+
+    #[test]
+    fn expand_response_files_at_signs() {
+        let path = temp_file("response", "FOO\nBAR\n");
+        let args = ["-v", &format!("@{}", path.display()), "@@literal", "@@@x"];
+        assert_eq!(
+            expand_response_files(args.iter().map(OsString::from).collect()).unwrap(),
+            ["-v", "FOO", "BAR", "@literal", "@@x"]
+        );
+        assert!(expand_response_files(vec!["@/nonexistent/args.txt".into()]).is_err());
+    }
+
+    #[test]
+    fn parse_env_line_quoting() {
+        let parse = |line| parse_env_line(line).unwrap();
+        assert_eq!(parse("KEY=value"), ("KEY".into(), "value".into()));
+        assert_eq!(parse(" KEY =  value  "), ("KEY".into(), "value".into()));
+        assert_eq!(parse("KEY="), ("KEY".into(), "".into()));
+        assert_eq!(parse("KEY=a=b"), ("KEY".into(), "a=b".into()));
+        // Unquoted values end at " #"
+        assert_eq!(parse("KEY=value # comment"), ("KEY".into(), "value".into()));
+        assert_eq!(parse("KEY=a#b"), ("KEY".into(), "a#b".into()));
+        // Single quotes keep the value as is
+        assert_eq!(
+            parse(r"KEY='a \n # b' ignored"),
+            ("KEY".into(), r"a \n # b".into())
+        );
+        assert_eq!(
+            parse(r#"KEY="a\n\t\r\\\"b" ignored"#),
+            ("KEY".into(), "a\n\t\r\\\"b".into())
+        );
+        assert!(parse_env_line("KEY").is_err());
+        assert!(parse_env_line("=value").is_err());
+        assert!(parse_env_line("KEY='value").is_err());
+        assert!(parse_env_line(r#"KEY="value"#).is_err());
+        assert!(parse_env_line(r#"KEY="value\"#).is_err());
+        assert!(parse_env_line(r#"KEY="\q""#).is_err());
+    }
+
+    #[test]
+    fn read_env_file_export() {
+        let path = temp_file(
+            "env",
+            "# comment\n\nexport HOME=/home/me\n  USER = me # comment\n",
+        );
+        assert_eq!(
+            read_env_file(&path).unwrap(),
+            [
+                ("HOME".into(), "/home/me".into()),
+                ("USER".into(), "me".into())
+            ]
+        );
+        let path = temp_file("env-error", "HOME=/home/me\nUSER\n");
+        let err = read_env_file(&path).unwrap_err().to_string();
+        assert!(
+            err.ends_with(":2: invalid line: USER (expected KEY=VALUE)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn parse_exit_code_mapping_zero() {
+        assert!(parse_exit_code_mapping("1=0").is_ok());
+        assert!(parse_exit_code_mapping("nonzero=2").is_ok());
+        assert!(parse_exit_code_mapping("0=1").is_err());
+    }
+
+    #[test]
+    fn split_escaped_words_whitespace() {
+        assert_eq!(split_escaped_words(r"a\ b c"), [r"a\ b", "c"]);
+        assert_eq!(split_escaped_words("  ls \t -l  "), ["ls", "-l"]);
+        assert_eq!(split_escaped_words(r"a\  b\\ c"), [r"a\ ", r"b\\", "c"]);
+        assert_eq!(split_escaped_words(r"trailing\ "), [r"trailing\ "]);
+        assert!(split_escaped_words("   ").is_empty());
+    }
+}
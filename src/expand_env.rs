@@ -0,0 +1,375 @@
+//! Expansion of `${NAME}` in the preset args with the environment variables given
+//! by the runtime.
+
+use walrus::{
+    ir::BinaryOp, FunctionId, GlobalId, InitExpr, InstrSeqBuilder, LocalId, MemoryId, Module,
+    ValType,
+};
+
+use crate::{
+    add_checked, add_string_size, copy_string, load_i32, store_bytes_at, store_i32,
+    string_matches_at, take_snapshot, usize_to_wasm_i32,
+};
+
+/// What to do with `${NAME}` in the preset args when the environment variable is not defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UndefinedEnvPolicy {
+    /// Expand to an empty string
+    #[default]
+    Empty,
+    /// Leave `${NAME}` as is
+    KeepLiteral,
+}
+
+impl std::str::FromStr for UndefinedEnvPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "empty" => Ok(UndefinedEnvPolicy::Empty),
+            "keep" => Ok(UndefinedEnvPolicy::KeepLiteral),
+            _ => anyhow::bail!(
+                "unknown undefined env policy: {} (expected empty or keep)",
+                s
+            ),
+        }
+    }
+}
+
+/// A piece of a preset arg
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Segment {
+    Literal(Vec<u8>),
+    /// `${NAME}`
+    Var(Vec<u8>),
+}
+
+impl Segment {
+    /// The text of the segment before expansion
+    fn literal_len(&self) -> usize {
+        match self {
+            Segment::Literal(s) => s.len(),
+            Segment::Var(name) => name.len() + "${}".len(),
+        }
+    }
+}
+
+/// Split a preset arg into literals and `${NAME}`s. `$${` is an escape for a literal `${`,
+/// and `$` not followed by a valid `{NAME}` is left as is.
+pub(crate) fn parse_template(arg: &[u8]) -> Vec<Segment> {
+    let mut segments = vec![];
+    let mut literal = vec![];
+    let mut rest = arg;
+    while !rest.is_empty() {
+        if rest.starts_with(b"$${") {
+            literal.extend_from_slice(b"${");
+            rest = &rest[3..];
+            continue;
+        }
+        if rest.starts_with(b"${") {
+            if let Some(end) = rest.iter().position(|&c| c == b'}') {
+                let name = &rest[2..end];
+                if !name.is_empty() && name.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+                {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Var(name.to_vec()));
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        }
+        literal.push(rest[0]);
+        rest = &rest[1..];
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    segments
+}
+
+/// Globals holding the snapshot of the environment variables.
+#[derive(Clone, Copy)]
+pub(crate) struct EnvSnapshot {
+    /// Pointer to the `environ` array, or NULL if not taken yet
+    pub(crate) environ: GlobalId,
+    /// The number of the environment variables
+    pub(crate) environc: GlobalId,
+}
+
+impl EnvSnapshot {
+    pub(crate) fn new(module: &mut Module) -> Self {
+        let mut add_i32_global = || {
+            module.globals.add_local(
+                ValType::I32,
+                true,
+                InitExpr::Value(walrus::ir::Value::I32(0)),
+            )
+        };
+        Self {
+            environ: add_i32_global(),
+            environc: add_i32_global(),
+        }
+    }
+
+    /// Take a snapshot of the environment variables if not taken yet.
+    /// `count_ptr` and `size_ptr` point to scratch memory for `environ_sizes_get`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn take(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        memory: MemoryId,
+        (environ_sizes_get, environ_get): (FunctionId, FunctionId),
+        (count_ptr, size_ptr): (LocalId, LocalId),
+        count: LocalId,
+        size: LocalId,
+        err: LocalId,
+        overflow_errno: u16,
+    ) {
+        builder.global_get(self.environ).if_else(
+            None,
+            |_| {},
+            |else_| {
+                // err = environ_sizes_get(count_ptr, size_ptr);
+                else_
+                    .local_get(count_ptr)
+                    .local_get(size_ptr)
+                    .call(environ_sizes_get)
+                    .local_tee(err)
+                    .if_else(
+                        None,
+                        |then| {
+                            then.local_get(err).return_();
+                        },
+                        |_| {},
+                    );
+                else_.local_get(count_ptr);
+                load_i32(else_, memory)
+                    .local_tee(count)
+                    .global_set(self.environc);
+                else_.local_get(size_ptr);
+                load_i32(else_, memory).local_set(size);
+                take_snapshot(
+                    else_,
+                    memory,
+                    environ_get,
+                    self.environ,
+                    count,
+                    size,
+                    err,
+                    overflow_errno,
+                );
+            },
+        );
+    }
+
+    /// Drop the environment variables for which `predicate (char *var) (result i32)` returns 0
+    /// from the snapshot, compacting the `environ` array.
+    pub(crate) fn retain(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        memory: MemoryId,
+        predicate: FunctionId,
+        index: LocalId,
+        out: LocalId,
+        p: LocalId,
+    ) {
+        // for (index = 0, out = 0; index < environc; index++) { ... }
+        builder.i32_const(0).local_tee(index).local_set(out);
+        builder.block(None, |done| {
+            let done_id = done.id();
+            done.loop_(None, |loop_| {
+                let loop_id = loop_.id();
+                loop_
+                    .local_get(index)
+                    .global_get(self.environc)
+                    .binop(BinaryOp::I32GeU)
+                    .br_if(done_id);
+                // char *p = environ[index++];
+                self.push_entry_ptr(loop_, index);
+                load_i32(loop_, memory).local_set(p);
+                loop_
+                    .local_get(index)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(index);
+                // if (predicate(p)) environ[out++] = p;
+                loop_.local_get(p).call(predicate).if_else(
+                    None,
+                    |then| {
+                        self.push_entry_ptr(then, out);
+                        then.local_get(p);
+                        store_i32(then, memory);
+                        then.local_get(out)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .local_set(out);
+                    },
+                    |_| {},
+                );
+                loop_.br(loop_id);
+            });
+        });
+        // environc = out;
+        builder.local_get(out).global_set(self.environc);
+    }
+
+    /// Push `&environ[index]`.
+    pub(crate) fn push_entry_ptr(&self, builder: &mut InstrSeqBuilder, index: LocalId) {
+        builder
+            .global_get(self.environ)
+            .local_get(index)
+            .i32_const(4)
+            .binop(BinaryOp::I32Mul)
+            .binop(BinaryOp::I32Add);
+    }
+
+    /// Push the pointer to the value of the environment variable `name`, or NULL if it's
+    /// not defined.
+    pub(crate) fn push_getenv(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        memory: MemoryId,
+        name: &[u8],
+        index: LocalId,
+        p: LocalId,
+    ) {
+        let prefix = [name, b"="].concat();
+        builder.block(ValType::I32, |found| {
+            let found_id = found.id();
+            // for (index = 0; index < environc; index++) { ... }
+            found.i32_const(0).local_set(index);
+            found.loop_(None, |loop_| {
+                let loop_id = loop_.id();
+                // if (index >= environc) return NULL;
+                loop_
+                    .i32_const(0)
+                    .local_get(index)
+                    .global_get(self.environc)
+                    .binop(BinaryOp::I32GeU)
+                    .br_if(found_id)
+                    .drop();
+                // char *p = environ[index++];
+                self.push_entry_ptr(loop_, index);
+                load_i32(loop_, memory).local_set(p);
+                loop_
+                    .local_get(index)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(index);
+                // if (strncmp(p, "NAME=", LEN) == 0) return p + LEN;
+                loop_
+                    .local_get(p)
+                    .const_(usize_to_wasm_i32(prefix.len()))
+                    .binop(BinaryOp::I32Add);
+                string_matches_at(loop_, memory, &prefix, p, false);
+                loop_.br_if(found_id).drop();
+                loop_.br(loop_id);
+            });
+            found.unreachable();
+        });
+    }
+}
+
+/// Code generator for the preset args containing `${NAME}`.
+pub(crate) struct Expander {
+    pub(crate) memory: MemoryId,
+    pub(crate) env: EnvSnapshot,
+    pub(crate) undefined: UndefinedEnvPolicy,
+    // Locals
+    pub(crate) index: LocalId,
+    pub(crate) p: LocalId,
+    pub(crate) byte: LocalId,
+}
+
+impl Expander {
+    /// `size += strlen(EXPANDED(segments)) + 1`, returning `overflow_errno` if it overflows
+    pub(crate) fn add_size(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        segments: &[Segment],
+        size: LocalId,
+        tmp: LocalId,
+        overflow_errno: u16,
+    ) {
+        let literal_size = segments
+            .iter()
+            .filter(|s| matches!(s, Segment::Literal(_)))
+            .map(Segment::literal_len)
+            .sum::<usize>();
+        // size += LITERALS_SIZE + 1;
+        builder.const_(usize_to_wasm_i32(literal_size + 1));
+        add_checked(builder, size, tmp, overflow_errno);
+        for segment in segments {
+            let name = match segment {
+                Segment::Var(name) => name,
+                Segment::Literal(_) => continue,
+            };
+            self.env
+                .push_getenv(builder, self.memory, name, self.index, self.p);
+            builder.local_tee(self.p).if_else(
+                None,
+                |then| {
+                    // size += strlen(p);
+                    then.i32_const(-1).local_set(self.index);
+                    add_string_size(then, self.memory, self.p, self.index, self.byte);
+                    then.local_get(self.index);
+                    add_checked(then, size, tmp, overflow_errno);
+                },
+                |else_| {
+                    if self.undefined == UndefinedEnvPolicy::KeepLiteral {
+                        // size += strlen("${NAME}");
+                        else_.const_(usize_to_wasm_i32(segment.literal_len()));
+                        add_checked(else_, size, tmp, overflow_errno);
+                    }
+                },
+            );
+        }
+    }
+
+    /// Write the expanded string at `cursor`, and advance it past the NUL.
+    pub(crate) fn write(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        segments: &[Segment],
+        cursor: LocalId,
+    ) {
+        for segment in segments {
+            match segment {
+                Segment::Literal(s) => self.write_literal(builder, s, cursor),
+                Segment::Var(name) => {
+                    self.env
+                        .push_getenv(builder, self.memory, name, self.index, self.p);
+                    builder.local_tee(self.p).if_else(
+                        None,
+                        |then| {
+                            // cursor = stpcpy(cursor, p);
+                            copy_string(then, self.memory, cursor, self.p, self.byte);
+                            then.local_get(cursor)
+                                .i32_const(1)
+                                .binop(BinaryOp::I32Sub)
+                                .local_set(cursor);
+                        },
+                        |else_| {
+                            if self.undefined == UndefinedEnvPolicy::KeepLiteral {
+                                let literal = [b"${", name.as_slice(), b"}"].concat();
+                                self.write_literal(else_, &literal, cursor);
+                            }
+                        },
+                    );
+                }
+            }
+        }
+        self.write_literal(builder, &[0], cursor);
+    }
+
+    fn write_literal(&self, builder: &mut InstrSeqBuilder, s: &[u8], cursor: LocalId) {
+        store_bytes_at(builder, self.memory, s, cursor, 0);
+        builder
+            .local_get(cursor)
+            .const_(usize_to_wasm_i32(s.len()))
+            .binop(BinaryOp::I32Add)
+            .local_set(cursor);
+    }
+}
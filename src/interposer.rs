@@ -0,0 +1,173 @@
+//! Interposing the functions imported by a module with proxy functions.
+
+use std::collections::HashMap;
+
+use walrus::{FunctionId, Module};
+
+use crate::{call_graph, get_import_function};
+
+/// Builds the proxy function given the original imported function
+type BuildProxy<'a> = Box<dyn FnOnce(&mut Module, FunctionId) -> anyhow::Result<FunctionId> + 'a>;
+
+struct Proxy<'a> {
+    module_name: String,
+    name: String,
+    required: bool,
+    build: BuildProxy<'a>,
+}
+
+/// Replaces the uses of the imported functions with proxy functions, which can still call the
+/// original ones. All the presets in this crate are built on this.
+///
+/// ## Example
+///
+/// Redirect the writes to the stderr to the stdout:
+///
+/// ```no_run
+/// use walrus::{ir::BinaryOp, FunctionBuilder};
+/// use wasi_preset_args::Interposer;
+///
+/// # fn main() -> anyhow::Result<()> {
+/// # let mut module = walrus::Module::from_file("my_program.wasm")?;
+/// let mut interposer = Interposer::new("my_shim");
+/// interposer.interpose("wasi_snapshot_preview1", "fd_write", |module, original| {
+///     let sig = module.types.get(module.funcs.get(original).ty()).clone();
+///     let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+///     builder.name("my_shim.fd_write".to_string());
+///     let args = sig
+///         .params()
+///         .iter()
+///         .map(|ty| module.locals.add(*ty))
+///         .collect::<Vec<_>>();
+///     let mut body = builder.func_body();
+///     // fd == 2 ? 1 : fd
+///     body.i32_const(1)
+///         .local_get(args[0])
+///         .local_get(args[0])
+///         .i32_const(2)
+///         .binop(BinaryOp::I32Eq)
+///         .select(None);
+///     for arg in &args[1..] {
+///         body.local_get(*arg);
+///     }
+///     body.call(original);
+///     Ok(builder.finish(args, &mut module.funcs))
+/// });
+/// interposer.run(&mut module)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Interposer<'a> {
+    /// Module name of the dummy imports marking the uses of the original functions
+    dummy_module_name: String,
+    proxies: Vec<Proxy<'a>>,
+}
+
+impl<'a> Interposer<'a> {
+    /// `dummy_module_name` is used to import the dummy functions temporarily during
+    /// [`Interposer::run`], e.g. "wasi_preset_args". It should be unique to the caller.
+    pub fn new(dummy_module_name: &str) -> Self {
+        Self {
+            dummy_module_name: dummy_module_name.to_string(),
+            proxies: vec![],
+        }
+    }
+
+    /// Replace the uses of the function imported as `module_name.name` with the proxy returned
+    /// by `build`. `build` gets the original function, which must be called by the proxy
+    /// instead of the uses. [`Interposer::run`] fails if the function is not imported.
+    pub fn interpose<F>(&mut self, module_name: &str, name: &str, build: F) -> &mut Self
+    where
+        F: FnOnce(&mut Module, FunctionId) -> anyhow::Result<FunctionId> + 'a,
+    {
+        self.push(module_name, name, true, Box::new(build))
+    }
+
+    /// Same as [`Interposer::interpose`], but does nothing if the function is not imported,
+    /// e.g. for `path_open` imported only by the programs opening files.
+    pub fn interpose_if_imported<F>(&mut self, module_name: &str, name: &str, build: F) -> &mut Self
+    where
+        F: FnOnce(&mut Module, FunctionId) -> anyhow::Result<FunctionId> + 'a,
+    {
+        self.push(module_name, name, false, Box::new(build))
+    }
+
+    fn push(
+        &mut self,
+        module_name: &str,
+        name: &str,
+        required: bool,
+        build: BuildProxy<'a>,
+    ) -> &mut Self {
+        self.proxies.push(Proxy {
+            module_name: module_name.to_string(),
+            name: name.to_string(),
+            required,
+            build,
+        });
+        self
+    }
+
+    /// Rewrite the module to use the proxies.
+    ///
+    /// The uses of the originals are first replaced with dummy imports, to distinguish them
+    /// from the uses in the proxies. Then the proxies are built in the order of
+    /// [`Interposer::interpose`], and the uses of the dummies are replaced with them.
+    /// So the functions added by `build` can call the originals, while the functions calling
+    /// the originals added before this are interposed as well.
+    pub fn run(self, module: &mut Module) -> anyhow::Result<()> {
+        let mut proxies = vec![];
+        for proxy in self.proxies {
+            let original = match get_import_function(module, &proxy.module_name, &proxy.name) {
+                Ok(original) => original,
+                Err(e) if proxy.required => return Err(e),
+                Err(_) => continue,
+            };
+            if proxies
+                .iter()
+                .any(|(interposed, _)| *interposed == original)
+            {
+                anyhow::bail!("{}.{} interposed twice", proxy.module_name, proxy.name);
+            }
+            proxies.push((original, proxy));
+        }
+        let dummies = proxies
+            .iter()
+            .map(|(original, proxy)| {
+                let ty = module.funcs.get(*original).ty();
+                // Named after the module too, so that the same name imported from two
+                // modules gets two dummies, e.g. "wasi_unstable.fd_write"
+                let name = format!("{}.{}", proxy.module_name, proxy.name);
+                module.add_import_func(&self.dummy_module_name, &name, ty)
+            })
+            .collect::<Vec<_>>();
+
+        let mut call_graph = call_graph::CallGraph::build_from(module);
+        {
+            // Replace the use of the original functions with dummy functions
+            // to distinguish them from the use of them in the proxy functions.
+            let map = proxies
+                .iter()
+                .zip(&dummies)
+                .map(|((original, _), (dummy, _))| (*original, *dummy))
+                .collect();
+
+            call_graph::replace_func_use(&map, module, &mut call_graph);
+        }
+        let mut map = HashMap::new();
+        for ((original, proxy), (dummy, _)) in proxies.into_iter().zip(&dummies) {
+            let new = (proxy.build)(module, original)?;
+            map.insert(*dummy, new);
+        }
+        // Replace the use of the dummy functions with the proxy functions.
+        // This doesn't replace the use of the original functions in the proxy
+        // functions, thanks to the dummy functions marking.
+        call_graph::replace_func_use(&map, module, &mut call_graph);
+
+        for (_, dummy_import) in dummies {
+            module.imports.delete(dummy_import);
+        }
+
+        Ok(())
+    }
+}
@@ -6,31 +6,212 @@
 //! ```no_run
 //! use wasi_preset_args::PresetArgs;
 //!
+//! # fn main() -> anyhow::Result<()> {
+//! # let mut module = walrus::Module::from_file("my_program.wasm")?;
 //! let preset_args = PresetArgs::new("my_program".into(), vec!["--arg1".into(), "--arg2".into()]);
 //! preset_args.run(&mut module)?;
+//! # Ok(())
+//! # }
 //! ```
 //! Then, the result program behaves as if "--arg1" and "--arg2" were passed to it, and
 //! the rest of the arguments are passed at the last.
 //! If a runtime does not provide a program name, "my_program" will be used as argv[0].
+//! (See [`Argv0Policy`] to always override argv[0] or to strip the directory part of it.)
 //!
 //! ```console
 //! $ wasmtime run ./my_program.wasm --arg3 # --arg1 --arg2 --arg3 is passed to the program
 //! ```
+//!
+//! The preset args can also be placed after the runtime args with [`Placement::Append`].
+//!
+//! The environment variables can be preset in the same way with [`PresetEnv`], the time read
+//! from the clocks with [`PresetClock`], the randomness with [`PresetRandom`], the stdin with
+//! [`PresetStdin`], and read-only files with [`PresetFiles`]. The exit code can be remapped
+//! with [`PresetExit`]. Other imported functions can be interposed with [`Interposer`].
 
-use std::{collections::HashMap, ffi::OsString};
+use std::ffi::OsString;
 
 use walrus::{
-    ir::{BinaryOp, LoadKind, MemArg, StoreKind, UnaryOp, Value},
+    ir::{BinaryOp, ExtendedLoad, LoadKind, MemArg, StoreKind, UnaryOp, Value},
     FunctionBuilder, FunctionId, GlobalId, InitExpr, InstrSeqBuilder, LocalId, MemoryId, Module,
     ValType,
 };
 
 mod call_graph;
+mod expand_env;
+mod interposer;
+mod preset_clock;
+mod preset_env;
+mod preset_exit;
+mod preset_files;
+mod preset_preopens;
+mod preset_random;
+mod preset_stdin;
+
+pub use expand_env::UndefinedEnvPolicy;
+use expand_env::{parse_template, EnvSnapshot, Expander, Segment};
+pub use interposer::Interposer;
+pub use preset_clock::{ClockId, PresetClock};
+pub use preset_env::{EnvPrecedence, PresetEnv};
+pub use preset_exit::{ExitCodePattern, PresetExit};
+pub use preset_files::PresetFiles;
+pub use preset_preopens::PresetPreopens;
+pub use preset_random::PresetRandom;
+pub use preset_stdin::PresetStdin;
+
+/// Where the preset args are placed relative to the args given by the runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placement {
+    /// `argv[0] <preset args> <runtime args>`
+    #[default]
+    Prepend,
+    /// `argv[0] <runtime args> <preset args>`
+    Append,
+    /// `argv[0] <first N runtime args> <preset args> <rest of runtime args>`
+    ///
+    /// Useful for subcommand-style programs (e.g. `After(1)` for `tool <subcommand> [flags]`).
+    /// See [`FewerArgsPolicy`] for the behavior when the runtime gives fewer than N args.
+    After(usize),
+    /// `argv[0] <preset args before "$@"> <runtime args> <preset args after "$@">`
+    ///
+    /// The preset args are a template where the `$@` arg marks where the runtime args are
+    /// spliced, e.g. `--flag $@ --trailing`. See [`PresetArgs::template`].
+    Template,
+}
+
+impl std::str::FromStr for Placement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prepend" => Ok(Placement::Prepend),
+            "append" => Ok(Placement::Append),
+            "template" => Ok(Placement::Template),
+            _ => match s.strip_prefix("after:") {
+                Some(n) => Ok(Placement::After(n.parse()?)),
+                None => anyhow::bail!(
+                    "unknown placement: {} (expected prepend, append, after:N or template)",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// What to do with [`Placement::After`] when the runtime gives fewer than N args (excluding argv[0]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FewerArgsPolicy {
+    /// Place the preset args after all of the runtime args.
+    #[default]
+    Append,
+    /// Don't add the preset args at all.
+    Skip,
+    /// Make `args_sizes_get` and `args_get` fail with `__WASI_ERRNO_INVAL`.
+    Error,
+}
+
+impl std::str::FromStr for FewerArgsPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(FewerArgsPolicy::Append),
+            "skip" => Ok(FewerArgsPolicy::Skip),
+            "error" => Ok(FewerArgsPolicy::Error),
+            _ => anyhow::bail!(
+                "unknown fewer args policy: {} (expected append, skip or error)",
+                s
+            ),
+        }
+    }
+}
+
+/// How the args given by the runtime are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RuntimeArgs {
+    /// Pass the runtime args to the program together with the preset args.
+    #[default]
+    Forward,
+    /// Drop all runtime args except argv[0], so that the program sees only
+    /// `argv[0] <preset args>` no matter what the host passes.
+    Sealed {
+        /// Also replace the runtime argv[0] with the program name.
+        replace_program_name: bool,
+    },
+}
+
+/// How argv[0] of the program is decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Argv0Policy {
+    /// Use argv[0] given by the runtime as is. If the runtime doesn't give it,
+    /// an empty string is used.
+    KeepRuntime,
+    /// Use argv[0] given by the runtime, or the program name if the runtime doesn't give it.
+    #[default]
+    Fallback,
+    /// Always use the program name.
+    AlwaysOverride,
+    /// Use the basename of argv[0] given by the runtime (the part after the last `/` or `\`),
+    /// or the program name if the runtime doesn't give it.
+    Basename,
+}
+
+impl std::str::FromStr for Argv0Policy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Argv0Policy::KeepRuntime),
+            "fallback" => Ok(Argv0Policy::Fallback),
+            "override" => Ok(Argv0Policy::AlwaysOverride),
+            "basename" => Ok(Argv0Policy::Basename),
+            _ => anyhow::bail!(
+                "unknown argv[0] policy: {} (expected keep, fallback, override or basename)",
+                s
+            ),
+        }
+    }
+}
+
+/// Pattern matched against each runtime arg, or the key of each runtime environment variable
+/// with [`PresetEnv::allow_host_var`] and [`PresetEnv::deny_host_var`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgPattern {
+    /// Matches the arg equal to the string
+    Exact(OsString),
+    /// Matches the args starting with the string
+    Prefix(OsString),
+}
+
+impl std::str::FromStr for ArgPattern {
+    type Err = anyhow::Error;
+
+    /// Parse a pattern. A trailing `*` makes it a prefix pattern, e.g. `--unsafe-*`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('*') {
+            Some(prefix) => Ok(ArgPattern::Prefix(prefix.into())),
+            None => Ok(ArgPattern::Exact(s.into())),
+        }
+    }
+}
 
 pub struct PresetArgs {
     program_name: OsString,
     args: Vec<Vec<u8>>,
     wasi_module_name: String,
+    placement: Placement,
+    fewer_args_policy: FewerArgsPolicy,
+    runtime_args: RuntimeArgs,
+    argv0_policy: Argv0Policy,
+    denied_args: Vec<ArgPattern>,
+    max_runtime_argc: Option<u32>,
+    max_argv_buf_size: Option<u32>,
+    limit_errno: u16,
+    default_only: bool,
+    multicall: Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+    expand_env: Option<UndefinedEnvPolicy>,
+    overridable_keys: Vec<Vec<u8>>,
+    separator: Option<Vec<u8>>,
 }
 
 impl PresetArgs {
@@ -43,15 +224,144 @@ impl PresetArgs {
             program_name,
             args,
             wasi_module_name: "wasi_snapshot_preview1".to_string(),
+            placement: Placement::default(),
+            fewer_args_policy: FewerArgsPolicy::default(),
+            runtime_args: RuntimeArgs::default(),
+            argv0_policy: Argv0Policy::default(),
+            denied_args: vec![],
+            max_runtime_argc: None,
+            max_argv_buf_size: None,
+            limit_errno: __WASI_ERRNO_2BIG as u16,
+            default_only: false,
+            multicall: vec![],
+            expand_env: None,
+            overridable_keys: vec![],
+            separator: None,
         }
     }
 
+    /// Set where the preset args are placed relative to the runtime args.
+    pub fn placement(&mut self, placement: Placement) -> &mut Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Use the args as a template where the `$@` arg marks where the runtime args (except
+    /// argv[0]) are spliced, e.g. `["--flag", "$@", "--trailing"]`. This replaces the preset
+    /// args given to [`PresetArgs::new`] and sets [`Placement::Template`]. The runtime args
+    /// follow the preset args if the template has no `$@`.
+    pub fn template(&mut self, template: Vec<OsString>) -> &mut Self {
+        self.args = template
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().as_bytes().to_vec())
+            .collect();
+        self.placement = Placement::Template;
+        self
+    }
+
+    /// Set what to do with [`Placement::After`] when the runtime gives fewer args than expected.
+    pub fn fewer_args_policy(&mut self, policy: FewerArgsPolicy) -> &mut Self {
+        self.fewer_args_policy = policy;
+        self
+    }
+
+    /// Set how argv[0] of the program is decided.
+    pub fn argv0_policy(&mut self, policy: Argv0Policy) -> &mut Self {
+        self.argv0_policy = policy;
+        self
+    }
+
+    /// Set how the runtime args are treated. The placement options are ignored when
+    /// the runtime args are [`RuntimeArgs::Sealed`].
+    pub fn runtime_args(&mut self, runtime_args: RuntimeArgs) -> &mut Self {
+        self.runtime_args = runtime_args;
+        self
+    }
+
+    /// Apply the preset args only when the runtime gives no args except argv[0], like the
+    /// default command of a container image. Otherwise, the runtime args are passed as is.
+    /// The runtime args dropped by [`PresetArgs::deny_runtime_arg`] are not counted.
+    pub fn default_only(&mut self, default_only: bool) -> &mut Self {
+        self.default_only = default_only;
+        self
+    }
+
+    /// Use `args` instead of the default preset args when the basename of argv[0] is `name`,
+    /// to select the behavior of a multicall binary like busybox. The default preset args
+    /// given to [`PresetArgs::new`] are used when no name matches. If multiple entries have
+    /// the same name, the first one wins.
+    pub fn multicall(&mut self, name: OsString, args: Vec<OsString>) -> &mut Self {
+        let args = args
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().as_bytes().to_vec())
+            .collect::<Vec<_>>();
+        self.multicall
+            .push((name.to_string_lossy().as_bytes().to_vec(), args));
+        self
+    }
+
+    /// Expand `${NAME}` in the preset args with the environment variables given by the
+    /// runtime when the program runs, e.g. `--cache-dir=${HOME}/.cache`. `$${` is an escape
+    /// for a literal `${`. `undefined` decides what undefined variables are expanded to.
+    pub fn expand_env(&mut self, undefined: UndefinedEnvPolicy) -> &mut Self {
+        self.expand_env = Some(undefined);
+        self
+    }
+
+    /// Make the preset args with the key (`--key=value` or `--key`) overridable: they are
+    /// dropped when any runtime arg has the same key, e.g. a preset `--log-level=info` is
+    /// dropped by `--log-level=debug` or `--log-level debug`. At most 32 keys are allowed.
+    pub fn overridable(&mut self, key: OsString) -> &mut Self {
+        self.overridable_keys
+            .push(key.to_string_lossy().as_bytes().to_vec());
+        self
+    }
+
+    /// Insert the separator (usually `--`) between the preset args and the runtime args
+    /// following them, to stop option parsing before the runtime args. The separator is
+    /// omitted when no runtime arg follows the preset args, e.g. with [`Placement::Append`].
+    /// [`PresetArgs::run`] fails with [`Placement::Template`], where the template decides
+    /// what surrounds the runtime args.
+    pub fn separator(&mut self, separator: OsString) -> &mut Self {
+        self.separator = Some(separator.to_string_lossy().as_bytes().to_vec());
+        self
+    }
+
+    /// Drop the runtime args matching the pattern before the program sees them.
+    /// argv[0] is never dropped.
+    pub fn deny_runtime_arg(&mut self, pattern: ArgPattern) -> &mut Self {
+        self.denied_args.push(pattern);
+        self
+    }
+
+    /// Fail `args_sizes_get` when the runtime gives more args than `max` (except argv[0]
+    /// and the denied args).
+    pub fn max_runtime_argc(&mut self, max: u32) -> &mut Self {
+        self.max_runtime_argc = Some(max);
+        self
+    }
+
+    /// Fail `args_sizes_get` when the total size of the args strings, including the preset
+    /// args and the program name, exceeds `max`.
+    pub fn max_argv_buf_size(&mut self, max: u32) -> &mut Self {
+        self.max_argv_buf_size = Some(max);
+        self
+    }
+
+    /// Set the WASI errno returned by `args_sizes_get` when a limit is exceeded or the
+    /// sizes overflow i32. Defaults to `__WASI_ERRNO_2BIG` (1).
+    pub fn limit_errno(&mut self, errno: u16) -> &mut Self {
+        self.limit_errno = errno;
+        self
+    }
+
     /// Instrument the input Wasm so that it can override WASI args_get and args_sizes.
     ///
     /// ## Code Shape
     ///
     /// This function will adds two WASI compatible args_* functions to the module.
-    /// They proxies the original functions and adds the preset args to the front of the args list.
+    /// They proxies the original functions and adds the preset args to the front (or the back
+    /// or the middle, see [`Placement`]) of the args list.
     /// The preset args data is encoded in const instruction's immediates to avoid memory allocation.
     /// (Adding a new data segment in a linked module would break memory layout, so we can't use memory)
     ///
@@ -77,6 +387,9 @@ impl PresetArgs {
     ///  (import "wasi_snapshot_preview1" "args_get" (func (param i32 i32) (result i32)))
     ///
     ///  (global $saved_original_argc (mut i32) (i32.const 0))
+    ///  (global $saved_original_argv_buf_size (mut i32) (i32.const 0))
+    ///  (global $saved_runtime_argc (mut i32) (i32.const 0))
+    ///  (global $saved_extra_argc (mut i32) (i32.const 0))
     ///
     ///  # pseudo-code
     ///  (func $__main_void (result i32)
@@ -85,108 +398,414 @@ impl PresetArgs {
     ///  )
     ///  (func $wasi_preset_args.args_sizes_get (size_t *argc_ptr, size_t *argv_buf_size_ptr) (result i32)
     ///     i32 err = $wasi_snapshot_preview1.args_sizes_get(argc_ptr, argv_buf_size);
-    ///     if (err == __WASI_ERRNO_SUCCESS) {
-    ///       i32 argc = *argc_ptr;
-    ///       $saved_original_argc = argc;
-    ///       if (argc == 0) {
-    ///         *argc_ptr = 1 /* program name */ + PRESET_ARGS_LEN();
-    ///         *argv_buf_size_ptr = PROGRAM_NAME_SIZE() + PRESET_ARGS_SIZE();
-    ///       } else {
-    ///         *argc_ptr = argc + PRESET_ARGS_LEN();
-    ///         *argv_buf_size_ptr += PRESET_ARGS_SIZE();
-    ///       }
-    ///       return __WASI_ERRNO_SUCCESS;
-    ///     } else {
+    ///     if (err != __WASI_ERRNO_SUCCESS) {
     ///       return err;
     ///     }
+    ///     i32 argc = *argc_ptr;
+    ///     $saved_original_argc = argc;
+    ///     $saved_original_argv_buf_size = *argv_buf_size_ptr;
+    ///     size_t size = PRESET_ARGS_SIZE() + PROGRAM_NAME_SIZE();
+    ///     $saved_runtime_argc = 0;
+    ///     if (argc != 0) {
+    ///       // Room for the runtime args
+    ///       if (__builtin_add_overflow(size, $saved_original_argv_buf_size, &size)) {
+    ///         return LIMIT_ERRNO();
+    ///       }
+    ///       $saved_runtime_argc = argc - 1; /* runtime args except argv[0] */
+    ///     }
+    ///     if ($saved_runtime_argc > MAX_RUNTIME_ARGC() || size > MAX_ARGV_BUF_SIZE()) {
+    ///       return LIMIT_ERRNO();
+    ///     }
+    ///     *argc_ptr = 1 /* argv[0] */ + $saved_runtime_argc + PRESET_ARGS_LEN();
+    ///     $saved_extra_argc = *argc_ptr - argc;
+    ///     *argv_buf_size_ptr = size;
+    ///     return __WASI_ERRNO_SUCCESS;
     ///  )
     ///  (func $wasi_preset_args.args_get (char **argv, char *argv_buf) (result i32)
+    ///     // argv_buf layout:
+    ///     // 1. argv_buf ..< argv_buf + PRESET_ARGS_SIZE(): preset args
+    ///     // 2. ..< + PROGRAM_NAME_SIZE(): program name
+    ///     // 3. ..< + $saved_original_argv_buf_size: runtime args
+    ///     //
+    ///     // The pointers to the runtime args are written at the end of argv. Each of them is
+    ///     // moved to the same or a lower index, so the unread ones are never overwritten.
+    ///
+    ///     char **out = argv;
+    ///     char **runtime_argv;
     ///     if ($saved_original_argc == 0) {
     ///       char *program_name = argv_buf + PRESET_ARGS_SIZE();
     ///       memcpy(program_name, PROGRAM_NAME_DATA(), PROGRAM_NAME_SIZE());
-    ///       argv[0] = program_name;
+    ///       *out = program_name;
     ///     } else {
-    ///       char **extra_argv = argv + PRESET_ARGS_LEN();
-    ///       err = $wasi_snapshot_preview1.args_get(extra_argv, argv_buf + PRESET_ARGS_SIZE());
-    ///       if (err == __WASI_ERRNO_SUCCESS) {
-    ///         argv[0] = extra_argv[0];
-    ///       } else {
+    ///       char *runtime_buf = argv_buf + PRESET_ARGS_SIZE() + PROGRAM_NAME_SIZE();
+    ///       runtime_argv = argv + $saved_extra_argc;
+    ///       err = $wasi_snapshot_preview1.args_get(runtime_argv, runtime_buf);
+    ///       if (err != __WASI_ERRNO_SUCCESS) {
     ///         return err;
     ///       }
+    ///       *out = runtime_argv[0];
     ///     }
+    ///     out++;
     ///
+    ///     i32 index = 1;
+    ///     // PLACEMENT == Prepend:
     ///     memcpy(argv_buf, PRESET_ARGS_DATA(), PRESET_ARGS_SIZE());
-    ///
-    ///     argv[1] = argv_buf + PRESET_ARGS_OFFSET(0);
-    ///     argv[2] = argv_buf + PRESET_ARGS_OFFSET(1);
+    ///     *out++ = argv_buf + PRESET_ARGS_OFFSET(0);
     ///     ...
-    ///     argv[PRESET_ARGS_LEN()] = argv_buf + PRESET_ARGS_OFFSET(PRESET_ARGS_LEN() - 1);
+    ///     *out++ = argv_buf + PRESET_ARGS_OFFSET(PRESET_ARGS_LEN() - 1);
+    ///     for (; index < $saved_original_argc; index++) {
+    ///       *out++ = runtime_argv[index];
+    ///     }
     ///
     ///     return __WASI_ERRNO_SUCCESS;
     ///  )
     /// )
     /// ```
     ///
+    /// With [`Placement::Append`], the preset args are written after the loop copying the
+    /// runtime args. With [`Placement::After`], the loop is split into two: the first one copies
+    /// at most N runtime args before the preset args, and the second one copies the rest after them.
+    /// If the runtime gives fewer than N args, the first loop copies all of them, so the preset args
+    /// are appended. With [`FewerArgsPolicy::Skip`] or [`FewerArgsPolicy::Error`], the preset args
+    /// are not written or `__WASI_ERRNO_INVAL` is returned instead.
+    ///
+    /// After the runtime argv[0] is written, it's rewritten according to the [`Argv0Policy`]:
+    /// [`Argv0Policy::AlwaysOverride`] writes the program name at `argv_buf + PRESET_ARGS_SIZE()`,
+    /// which is reserved in every layout, and [`Argv0Policy::Basename`] points argv[0] into the
+    /// middle of the original string. So the buffer size doesn't change, and the discarded bytes
+    /// are just left unused.
+    ///
+    /// With [`Placement::Template`], the preset args before `$@` are written before the loop,
+    /// and the ones after `$@` are written after it. The `$@` itself is not written nor counted.
+    ///
+    /// With [`PresetArgs::separator`], the separator is written after the preset args if
+    /// `$saved_runtime_argc` is larger than the number of the runtime args before them, and
+    /// counted in `args_sizes_get` in the same condition. Its data is placed at the end of
+    /// the space for the preset args.
+    ///
+    /// With [`PresetArgs::default_only`], the preset args are written (and counted in
+    /// `args_sizes_get`) only when `$saved_runtime_argc` is 0, in the same way as
+    /// [`FewerArgsPolicy::Skip`].
+    ///
+    /// With [`RuntimeArgs::Sealed`], `$saved_runtime_argc` is always 0, and the loops copying the
+    /// runtime args are not emitted, so only argv[0] is taken from the runtime args. As argv has
+    /// no room for all the runtime args, `args_sizes_get` reserves
+    /// `sizeof(char *) - 1 + argc * sizeof(char *)` more bytes in argv_buf, and `runtime_argv`
+    /// points to the first address aligned to `sizeof(char *)` after the runtime args. When
+    /// `replace_program_name` is set, the original `args_get` is not called at all.
+    ///
+    /// ### Runtime args snapshot
+    ///
+    /// When the runtime args are filtered with [`PresetArgs::deny_runtime_arg`], `args_sizes_get`
+    /// has to see their contents to compute the argc and argv_buf size. In this case,
+    /// `args_sizes_get` grows the memory to take a snapshot of the runtime args by the original
+    /// `args_get`, and saves the pointer to them in `$saved_original_argv`:
+    ///
+    /// ```c
+    /// if ($saved_original_argv == NULL) {
+    ///   i32 pages = ($saved_original_argv_buf_size + argc * sizeof(char *) + 0xffff) / 0x10000;
+    ///   i32 base = memory.grow(pages);
+    ///   if (base == -1) {
+    ///     return __WASI_ERRNO_NOMEM;
+    ///   }
+    ///   $saved_original_argv = base * 0x10000;
+    ///   err = $wasi_snapshot_preview1.args_get($saved_original_argv, $saved_original_argv + argc);
+    ///   if (err != __WASI_ERRNO_SUCCESS) {
+    ///     return err;
+    ///   }
+    /// }
+    /// size += strlen($saved_original_argv[0]) + 1;
+    /// for (i32 index = 1; index < argc; index++) {
+    ///   char *arg = $saved_original_argv[index];
+    ///   if (!$wasi_preset_args.is_denied_arg(arg)) {
+    ///     $saved_runtime_argc++;
+    ///     size += strlen(arg) + 1;
+    ///   }
+    /// }
+    /// ```
+    ///
+    /// With [`PresetArgs::multicall`], the basename of `$saved_original_argv[0]` is compared
+    /// with the names in the table, and the index of the matched set (or 0 for the default set)
+    /// is saved in `$saved_preset_set`. `PRESET_ARGS_LEN()` and the preset args data are chosen
+    /// by it in both functions, and `PRESET_ARGS_SIZE()` is the largest size among the sets.
+    ///
+    /// ### Environment variable expansion
+    ///
+    /// With [`PresetArgs::expand_env`], `args_sizes_get` also takes a snapshot of the environment
+    /// variables in the same way, and computes the size of the preset args with `${NAME}`
+    /// expanded. The expanded preset args are written at the end of argv_buf:
+    ///
+    /// ```c
+    /// // in $wasi_preset_args.args_sizes_get
+    /// $saved_expanded_size = strlen(EXPANDED_ARG(0)) + 1 + ...;
+    /// size += $saved_expanded_size;
+    /// ...
+    /// $saved_argv_buf_size = size;
+    ///
+    /// // in $wasi_preset_args.args_get
+    /// char *expanded = argv_buf + $saved_argv_buf_size - $saved_expanded_size;
+    /// ...
+    /// out[i] = expanded;
+    /// expanded = stpcpy(expanded, EXPANDED_ARG(i)) + 1;
+    /// ```
+    ///
+    /// With [`PresetArgs::overridable`], the runtime args are compared with the overridable
+    /// keys in the loop above, and the bits of the matched keys are set in `$saved_overridden`.
+    /// The preset args with the keys are skipped in `args_get` and subtracted from
+    /// `PRESET_ARGS_LEN()` in `args_sizes_get` if the bits are set.
+    ///
+    /// The limits are checked after the filtering, so the denied args don't count toward them.
+    ///
+    /// Then `args_get` doesn't call the original `args_get` but copies the runtime args that are
+    /// not denied from the snapshot into `argv_buf + PRESET_ARGS_SIZE() + PROGRAM_NAME_SIZE()`.
+    ///
     /// ## Limitations
     ///
     /// This rewrite assumes that `args_get` is always called after `args_sizes_get` to save the
     /// original argc in a global variable, which is used to determine whether the runtime provides
     /// program name or not.
     ///
+    /// The memory grown for the runtime args snapshot is never released.
+    ///
     pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
-        // Add the global variable to store the original argc.
-        let saved_original_argc =
+        if self.placement == Placement::Template {
+            if self.separator.is_some() {
+                anyhow::bail!("the separator can't be used with the template placement");
+            }
+            for args in self.preset_sets() {
+                if args.iter().filter(|arg| is_splice_marker(arg)).count() > 1 {
+                    anyhow::bail!("multiple $@ in the template: {:?}", args_to_strings(args));
+                }
+            }
+        }
+        if self.overridable_keys.len() > 32 {
+            anyhow::bail!(
+                "too many overridable keys: {} (at most 32)",
+                self.overridable_keys.len()
+            );
+        }
+        let expansion = if self.expands_env() {
+            Some(SavedExpansion {
+                env: EnvSnapshot::new(module),
+                expanded_size: module.globals.add_local(
+                    ValType::I32,
+                    true,
+                    InitExpr::Value(Value::I32(0)),
+                ),
+                argv_buf_size: module.globals.add_local(
+                    ValType::I32,
+                    true,
+                    InitExpr::Value(Value::I32(0)),
+                ),
+            })
+        } else {
+            None
+        };
+        // Add the global variables to store the original args sizes.
+        let mut add_i32_global = || {
             module
                 .globals
-                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
-
-        let original_args_sizes_get =
-            get_import_function(module, &self.wasi_module_name, "args_sizes_get")?;
-        let (dummy_args_sizes_get, dummy_args_sizes_get_import) = module.add_import_func(
-            "wasi_preset_args",
-            "args_sizes_get",
-            module.funcs.get(original_args_sizes_get).ty(),
-        );
-        let original_args_get = get_import_function(module, &self.wasi_module_name, "args_get")?;
-        let (dummy_args_get, dummy_args_get_import) = module.add_import_func(
-            "wasi_preset_args",
-            "args_get",
-            module.funcs.get(original_args_get).ty(),
-        );
+                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)))
+        };
+        let saved = SavedArgs {
+            original_argc: add_i32_global(),
+            original_argv_buf_size: add_i32_global(),
+            runtime_argc: add_i32_global(),
+            original_argv: if self.snapshots_runtime_args() {
+                Some(add_i32_global())
+            } else {
+                None
+            },
+            extra_argc: if self.fetches_runtime_args_into_argv() {
+                Some(add_i32_global())
+            } else {
+                None
+            },
+            preset_set: if self.multicall.is_empty() {
+                None
+            } else {
+                Some(add_i32_global())
+            },
+            overridden: if self.overridable_keys.is_empty() {
+                None
+            } else {
+                Some(add_i32_global())
+            },
+            expansion,
+        };
 
-        let mut call_graph = call_graph::CallGraph::build_from(module);
-        {
-            // Replace the use of the original `args_*` with dummy functions
-            // to distinguish them from the use of them in our proxy functions.
-            let mut map = HashMap::new();
-            map.insert(original_args_sizes_get, dummy_args_sizes_get);
-            map.insert(original_args_get, dummy_args_get);
+        let is_denied_arg = self.add_is_denied_arg(module)?;
+        let mut interposer = Interposer::new("wasi_preset_args");
+        interposer
+            .interpose(&self.wasi_module_name, "args_sizes_get", |module, _| {
+                self.add_args_sizes_get(module, &saved, is_denied_arg)
+            })
+            .interpose(&self.wasi_module_name, "args_get", |module, _| {
+                self.add_args_get(module, &saved, is_denied_arg)
+            });
+        interposer.run(module)
+    }
 
-            call_graph::replace_func_use(&map, module, &mut call_graph);
+    /// Returns N if the preset args are placed after the first N (> 0) runtime args
+    /// and the runtime may give fewer args than that.
+    fn fewer_args_threshold(&self) -> Option<usize> {
+        match (self.runtime_args, self.placement) {
+            (RuntimeArgs::Forward, Placement::After(n)) if n > 0 => Some(n),
+            _ => None,
         }
-        let new_args_sizes_get = self.add_args_sizes_get(module, saved_original_argc)?;
-        let new_args_get = self.add_args_get(module, saved_original_argc)?;
-        {
-            // Replace the use of the dummy functions with the proxy functions.
-            // This doesn't replace the use of the original functions in the proxy
-            // functions, thanks to the dummy functions marking.
-            let mut map = HashMap::new();
-            map.insert(dummy_args_sizes_get, new_args_sizes_get);
-            map.insert(dummy_args_get, new_args_get);
-            call_graph::replace_func_use(&map, module, &mut call_graph);
+    }
+
+    /// Whether `args_sizes_get` needs to see the contents of the runtime args.
+    fn snapshots_runtime_args(&self) -> bool {
+        !self.denied_args.is_empty()
+            || !self.multicall.is_empty()
+            || !self.overridable_keys.is_empty()
+    }
+
+    /// Index of the overridable key of the preset arg: `--key` of `--key=value` or `--key`.
+    fn override_key_index(&self, arg: &[u8]) -> Option<usize> {
+        let key = match arg.iter().position(|&c| c == b'=') {
+            Some(i) => &arg[..i],
+            None => arg,
+        };
+        self.overridable_keys.iter().position(|k| k == key)
+    }
+
+    /// Whether any preset arg has `${NAME}` to expand at runtime.
+    fn expands_env(&self) -> bool {
+        self.preset_sets()
+            .iter()
+            .flat_map(|args| args.iter())
+            .any(|arg| matches!(self.preset_arg(arg), PresetArg::Expanded(_)))
+    }
+
+    fn preset_arg(&self, arg: &[u8]) -> PresetArg {
+        if self.expand_env.is_none() {
+            return PresetArg::Static(arg.to_vec());
+        }
+        let segments = parse_template(arg);
+        if segments.iter().any(|s| matches!(s, Segment::Var(_))) {
+            return PresetArg::Expanded(segments);
         }
+        PresetArg::Static(
+            segments
+                .into_iter()
+                .flat_map(|s| match s {
+                    Segment::Literal(s) => s,
+                    Segment::Var(_) => unreachable!(),
+                })
+                .collect(),
+        )
+    }
+
+    /// The preset args in the set, excluding the `$@` of the template.
+    fn preset_args_in<'a>(&self, args: &'a [Vec<u8>]) -> impl Iterator<Item = &'a Vec<u8>> {
+        let template = self.placement == Placement::Template;
+        args.iter()
+            .filter(move |arg| !(template && is_splice_marker(arg)))
+    }
+
+    /// The part of the preset args in the set and its offset in the space for the preset args.
+    fn template_part<'a>(&self, args: &'a [Vec<u8>], part: TemplatePart) -> (&'a [Vec<u8>], usize) {
+        let splice = match self.placement {
+            Placement::Template => args.iter().position(|arg| is_splice_marker(arg)),
+            _ => None,
+        };
+        match (part, splice) {
+            (TemplatePart::Whole, _) | (TemplatePart::Head, None) => (args, 0),
+            (TemplatePart::Head, Some(splice)) => (&args[..splice], 0),
+            (TemplatePart::Tail, None) => (&[], 0),
+            (TemplatePart::Tail, Some(splice)) => {
+                let offset = args[..splice].iter().map(|arg| arg.len() + 1).sum();
+                (&args[splice + 1..], offset)
+            }
+        }
+    }
 
-        module.imports.delete(dummy_args_sizes_get_import);
-        module.imports.delete(dummy_args_get_import);
+    /// The preset args sets: the default one first, and then the multicall ones.
+    fn preset_sets(&self) -> Vec<&[Vec<u8>]> {
+        std::iter::once(self.args.as_slice())
+            .chain(self.multicall.iter().map(|(_, args)| args.as_slice()))
+            .collect()
+    }
+
+    /// Index of the preset args set for the argv[0], used when it's known at instrumentation.
+    fn preset_set_for(&self, argv0: &[u8]) -> usize {
+        let basename = match argv0.iter().rposition(|&c| c == b'/' || c == b'\\') {
+            Some(i) => &argv0[i + 1..],
+            None => argv0,
+        };
+        self.multicall
+            .iter()
+            .position(|(name, _)| name.as_slice() == basename)
+            .map_or(0, |i| i + 1)
+    }
+
+    /// Whether the original `args_get` is called to take the runtime args.
+    fn fetches_runtime_args(&self) -> bool {
+        !matches!(
+            self.runtime_args,
+            RuntimeArgs::Sealed {
+                replace_program_name: true
+            }
+        )
+    }
+
+    /// Whether the original `args_get` writes the pointers to the runtime args into argv,
+    /// which has room for all of them unless they are sealed.
+    fn fetches_runtime_args_into_argv(&self) -> bool {
+        self.runtime_args == RuntimeArgs::Forward && !self.snapshots_runtime_args()
+    }
+
+    /// Whether the original `args_get` writes the pointers to the runtime args into the end
+    /// of argv_buf, aligned to `sizeof(char *)`.
+    fn fetches_runtime_args_into_argv_buf(&self) -> bool {
+        self.fetches_runtime_args()
+            && !self.snapshots_runtime_args()
+            && !self.fetches_runtime_args_into_argv()
+    }
 
-        Ok(())
+    fn program_name_bytes(&self) -> Vec<u8> {
+        self.program_name.to_string_lossy().as_bytes().to_vec()
     }
 
+    /// argv[0] used when the runtime doesn't give it.
+    fn fallback_argv0(&self) -> Vec<u8> {
+        match self.argv0_policy {
+            Argv0Policy::KeepRuntime => vec![],
+            _ => self.program_name_bytes(),
+        }
+    }
+
+    /// Size of the space reserved for the preset args, which is enough for any of the sets.
     fn preset_args_size(&self) -> usize {
-        self.args.iter().map(|arg| arg.len() + 1).sum::<usize>()
+        self.separator_offset() + self.separator.as_ref().map_or(0, |sep| sep.len() + 1)
+    }
+    /// The separator is placed after the space for the preset args.
+    fn separator_offset(&self) -> usize {
+        self.preset_sets()
+            .iter()
+            .map(|args| {
+                self.preset_args_in(args)
+                    .map(|arg| arg.len() + 1)
+                    .sum::<usize>()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns N if the separator is inserted when the runtime gives more than N args,
+    /// that is, when any runtime arg follows the preset args.
+    fn separator_threshold(&self) -> Option<usize> {
+        self.separator.as_ref()?;
+        match (self.runtime_args, self.placement) {
+            (RuntimeArgs::Forward, Placement::Prepend) => Some(0),
+            (RuntimeArgs::Forward, Placement::After(n)) => Some(n),
+            _ => None,
+        }
     }
     fn argv_buf_size(&self) -> usize {
-        self.program_name.len() + 1 + self.preset_args_size()
+        self.program_name_bytes().len() + 1 + self.preset_args_size()
     }
     fn pointer_size(&self) -> usize {
         4
@@ -198,264 +817,1120 @@ impl PresetArgs {
         ))
     }
 
+    /// Add `$wasi_preset_args.is_denied_arg (char *arg) (result i32)` returning 1 if the arg
+    /// matches any of the denied patterns. Returns `None` if there is no denied pattern.
+    fn add_is_denied_arg(&self, module: &mut Module) -> anyhow::Result<Option<FunctionId>> {
+        if self.denied_args.is_empty() {
+            return Ok(None);
+        }
+        let memory = first_memory(module)?;
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let arg = module.locals.add(ValType::I32);
+        builder.name("wasi_preset_args.is_denied_arg".to_string());
+
+        let mut body = builder.func_body();
+        for pattern in &self.denied_args {
+            let (s, exact) = match pattern {
+                ArgPattern::Exact(s) => (s, true),
+                ArgPattern::Prefix(s) => (s, false),
+            };
+            // if (strmatch(arg, PATTERN)) return 1;
+            string_matches_at(
+                &mut body,
+                memory,
+                s.to_string_lossy().as_bytes(),
+                arg,
+                exact,
+            );
+            body.if_else(
+                None,
+                |then| {
+                    then.i32_const(1).return_();
+                },
+                |_| {},
+            );
+        }
+        body.i32_const(0);
+        Ok(Some(builder.finish(vec![arg], &mut module.funcs)))
+    }
+
     /// See the comment in `run` for the Code Shape.
     fn add_args_sizes_get(
         &self,
         module: &mut Module,
-        saved_original_argc: GlobalId,
+        saved: &SavedArgs,
+        is_denied_arg: Option<FunctionId>,
     ) -> anyhow::Result<FunctionId> {
         let original = get_import_function(module, &self.wasi_module_name, "args_sizes_get")?;
+        let original_args_get = get_import_function(module, &self.wasi_module_name, "args_get")?;
+        // Import `environ_*` only when expanding, as the runtime may not provide them.
+        let environ_functions = saved.expansion.as_ref().map(|_| {
+            (
+                get_or_add_import_function(module, &self.wasi_module_name, "environ_sizes_get"),
+                get_or_add_import_function(module, &self.wasi_module_name, "environ_get"),
+            )
+        });
         let sig = module.types.get(module.funcs.get(original).ty()).clone();
         let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
 
         // Arguments
         let argc_ptr = module.locals.add(ValType::I32);
         let argv_buf_size_ptr = module.locals.add(ValType::I32);
-        // Locals
-        let err = module.locals.add(ValType::I32);
-        let argc = module.locals.add(ValType::I32);
 
-        let memory = match module.memories.iter().next() {
-            Some(m) => m,
-            None => anyhow::bail!("no memory"),
+        let memory = first_memory(module)?;
+        let cx = SizesGetContext {
+            memory,
+            original_args_get,
+            is_denied_arg,
+            err: module.locals.add(ValType::I32),
+            argc: module.locals.add(ValType::I32),
+            size: module.locals.add(ValType::I32),
+            index: module.locals.add(ValType::I32),
+            arg: module.locals.add(ValType::I32),
+            base: module.locals.add(ValType::I32),
+            runtime_size: module.locals.add(ValType::I32),
+            expanded_size: module.locals.add(ValType::I32),
+            expander: None,
+        };
+        let cx = SizesGetContext {
+            expander: saved.expansion.as_ref().map(|expansion| Expander {
+                memory,
+                env: expansion.env,
+                undefined: self.expand_env.unwrap_or_default(),
+                index: cx.index,
+                p: cx.arg,
+                byte: cx.err,
+            }),
+            ..cx
         };
 
         builder.name("wasi_preset_args.args_sizes_get".to_string());
 
-        let mut instr_builder = builder.func_body();
+        let mut body = builder.func_body();
 
         // i32 err = $wasi_snapshot_preview1.args_sizes_get(argc_ptr, argv_buf_size);
-        instr_builder
-            .local_get(argc_ptr)
+        body.local_get(argc_ptr)
             .local_get(argv_buf_size_ptr)
             .call(original)
-            .local_tee(err);
+            .local_tee(cx.err)
+            .if_else(
+                None,
+                |then| {
+                    then.local_get(cx.err).return_();
+                },
+                |_| {},
+            );
 
-        // if (err != __WASI_ERRNO_SUCCESS) {
-        instr_builder.unop(UnaryOp::I32Eqz).if_else(
-            ValType::I32,
-            |then| {
-                // i32 argc = *argc_ptr;
-                then.local_get(argc_ptr)
-                    .load(
-                        memory.id(),
-                        LoadKind::I32 { atomic: false },
-                        MemArg {
-                            align: 1,
-                            offset: 0,
-                        },
-                    )
-                    .local_tee(argc);
-
-                // saved_original_argc = argc;
-                then.global_set(saved_original_argc);
-
-                // if (argc == 0) {
-                then.local_get(argc)
-                    .unop(UnaryOp::I32Eqz)
-                    .if_else(
-                        None,
-                        |then| {
-                            // *argc_ptr = 1 /* program name */ + PRESET_ARGS_LEN();
-                            then.local_get(argc_ptr)
-                                .const_(usize_to_wasm_i32(1 + self.args.len()))
-                                .store(
-                                    memory.id(),
-                                    StoreKind::I32 { atomic: false },
-                                    MemArg {
-                                        align: 1,
-                                        offset: 0,
-                                    },
-                                );
-                            // *argv_buf_size_ptr = PROGRAM_NAME_SIZE() + PRESET_ARGS_SIZE();
-                            then.local_get(argv_buf_size_ptr)
-                                .const_(usize_to_wasm_i32(self.argv_buf_size()))
-                                .store(
-                                    memory.id(),
-                                    StoreKind::I32 { atomic: false },
-                                    MemArg {
-                                        align: 1,
-                                        offset: 0,
-                                    },
-                                );
-                        },
-                        |_else| {
-                            // *argc_ptr = argc + PRESET_ARGS_LEN();
-                            _else
-                                .local_get(argc_ptr)
-                                .local_get(argc)
-                                .const_(usize_to_wasm_i32(self.args.len()))
-                                .binop(BinaryOp::I32Add)
-                                .store(
-                                    memory.id(),
-                                    StoreKind::I32 { atomic: false },
-                                    MemArg {
-                                        align: 1,
-                                        offset: 0,
-                                    },
-                                );
-                            // *argv_buf_size_ptr += PRESET_ARGS_SIZE();
-                            _else
-                                .local_get(argv_buf_size_ptr)
-                                .local_get(argv_buf_size_ptr)
-                                .load(
-                                    memory.id(),
-                                    LoadKind::I32 { atomic: false },
-                                    MemArg {
-                                        align: 1,
-                                        offset: 0,
-                                    },
-                                )
-                                .const_(self.argv_buf_size_value())
-                                .binop(BinaryOp::I32Add)
-                                .store(
-                                    memory.id(),
-                                    StoreKind::I32 { atomic: false },
-                                    MemArg {
-                                        align: 1,
-                                        offset: 0,
-                                    },
-                                );
-                        },
-                    )
-                    .i32_const(__WASI_ERRNO_SUCCESS);
-            },
-            |else_| {
-                else_.local_get(err);
-            },
-        );
+        // i32 argc = *argc_ptr;
+        body.local_get(argc_ptr);
+        load_i32(&mut body, memory).local_set(cx.argc);
+        // $saved_original_argc = argc;
+        body.local_get(cx.argc).global_set(saved.original_argc);
+        // $saved_original_argv_buf_size = *argv_buf_size_ptr;
+        body.local_get(argv_buf_size_ptr);
+        load_i32(&mut body, memory).global_set(saved.original_argv_buf_size);
+
+        // size_t size = PRESET_ARGS_SIZE() + PROGRAM_NAME_SIZE();
+        body.const_(self.argv_buf_size_value()).local_set(cx.size);
+        // $saved_runtime_argc = 0;
+        body.i32_const(0).global_set(saved.runtime_argc);
+        if let Some(overridden) = saved.overridden {
+            // $saved_overridden = 0;
+            body.i32_const(0).global_set(overridden);
+        }
+        if let Some(preset_set) = saved.preset_set {
+            // $saved_preset_set = PRESET_SET_FOR(basename(STATIC_ARGV0()));
+            let argv0 = if self.fetches_runtime_args() {
+                self.fallback_argv0()
+            } else {
+                self.program_name_bytes()
+            };
+            body.const_(usize_to_wasm_i32(self.preset_set_for(&argv0)))
+                .global_set(preset_set);
+        }
+
+        if self.fetches_runtime_args() {
+            // if (argc != 0) { ... }
+            body.local_get(cx.argc).if_else(
+                None,
+                |then| {
+                    // if (argc > UINT32_MAX / sizeof(char *)) return LIMIT_ERRNO();
+                    then.local_get(cx.argc)
+                        .const_(usize_to_wasm_i32(u32::MAX as usize / self.pointer_size()))
+                        .binop(BinaryOp::I32GtU)
+                        .if_else(
+                            None,
+                            |then| {
+                                then.i32_const(self.limit_errno.into()).return_();
+                            },
+                            |_| {},
+                        );
+                    self.add_runtime_args_size(then, &cx, saved);
+                },
+                |_| {},
+            );
+        }
+
+        if let (Some(expansion), Some(expander), Some(environ_functions)) =
+            (&saved.expansion, &cx.expander, environ_functions)
+        {
+            expansion.env.take(
+                &mut body,
+                memory,
+                environ_functions,
+                (argc_ptr, argv_buf_size_ptr),
+                cx.index,
+                cx.runtime_size,
+                cx.err,
+                self.limit_errno,
+            );
+            // size_t expanded_size = strlen(EXPANDED(0)) + 1 + ...;
+            body.i32_const(0).local_set(cx.expanded_size);
+            self.dispatch_preset_set(&mut body, saved, None, &|builder, args| {
+                for arg in self.preset_args_in(args) {
+                    if let PresetArg::Expanded(segments) = self.preset_arg(arg) {
+                        expander.add_size(
+                            builder,
+                            &segments,
+                            cx.expanded_size,
+                            cx.runtime_size,
+                            self.limit_errno,
+                        );
+                    }
+                }
+            });
+            body.local_get(cx.expanded_size)
+                .global_set(expansion.expanded_size);
+            // size += expanded_size;
+            body.local_get(cx.expanded_size);
+            add_checked(&mut body, cx.size, cx.err, self.limit_errno);
+        }
+
+        if let Some(max) = self.max_runtime_argc {
+            // if ($saved_runtime_argc > MAX_RUNTIME_ARGC()) return LIMIT_ERRNO();
+            body.global_get(saved.runtime_argc)
+                .i32_const(max as i32)
+                .binop(BinaryOp::I32GtU)
+                .if_else(
+                    None,
+                    |then| {
+                        then.i32_const(self.limit_errno.into()).return_();
+                    },
+                    |_| {},
+                );
+        }
+        if let Some(max) = self.max_argv_buf_size {
+            // if (size > MAX_ARGV_BUF_SIZE()) return LIMIT_ERRNO();
+            body.local_get(cx.size)
+                .i32_const(max as i32)
+                .binop(BinaryOp::I32GtU)
+                .if_else(
+                    None,
+                    |then| {
+                        then.i32_const(self.limit_errno.into()).return_();
+                    },
+                    |_| {},
+                );
+        }
+
+        if self.fetches_runtime_args_into_argv_buf() {
+            // Room for the aligned pointers to the runtime args, which is not counted in
+            // the limit
+            // size += sizeof(char *) - 1 + argc * sizeof(char *);
+            body.local_get(cx.argc)
+                .const_(usize_to_wasm_i32(self.pointer_size()))
+                .binop(BinaryOp::I32Mul)
+                .const_(usize_to_wasm_i32(self.pointer_size() - 1))
+                .binop(BinaryOp::I32Add);
+            add_checked(&mut body, cx.size, cx.err, self.limit_errno);
+        }
+
+        // *argc_ptr = 1 /* argv[0] */ + $saved_runtime_argc + PRESET_ARGS_LEN();
+        body.local_get(argc_ptr).global_get(saved.runtime_argc);
+        self.dispatch_preset_set(&mut body, saved, Some(ValType::I32), &|builder, args| {
+            builder.const_(usize_to_wasm_i32(1 + self.preset_args_in(args).count()));
+            if let Some(overridden) = saved.overridden {
+                for key in self
+                    .preset_args_in(args)
+                    .filter_map(|arg| self.override_key_index(arg))
+                {
+                    // - (($saved_overridden >> KEY) & 1)
+                    builder
+                        .global_get(overridden)
+                        .i32_const(key as i32)
+                        .binop(BinaryOp::I32ShrU)
+                        .i32_const(1)
+                        .binop(BinaryOp::I32And)
+                        .binop(BinaryOp::I32Sub);
+                }
+            }
+        });
+        body.binop(BinaryOp::I32Add);
+        if let Some(n) = self.separator_threshold() {
+            // + ($saved_runtime_argc > N) /* separator */
+            body.global_get(saved.runtime_argc)
+                .const_(usize_to_wasm_i32(n))
+                .binop(BinaryOp::I32GtU)
+                .binop(BinaryOp::I32Add);
+        }
+        store_i32(&mut body, memory);
+
+        if let (Some(n), FewerArgsPolicy::Error) =
+            (self.fewer_args_threshold(), self.fewer_args_policy)
+        {
+            // if ($saved_runtime_argc < N) return __WASI_ERRNO_INVAL;
+            body.global_get(saved.runtime_argc)
+                .const_(usize_to_wasm_i32(n))
+                .binop(BinaryOp::I32LtU)
+                .if_else(
+                    None,
+                    |then| {
+                        then.i32_const(__WASI_ERRNO_INVAL).return_();
+                    },
+                    |_| {},
+                );
+        }
+        if self.push_presets_applied(&mut body, saved) {
+            // if (!PRESETS_APPLIED()) { *argc_ptr = 1 /* argv[0] */ + $saved_runtime_argc; }
+            body.if_else(
+                None,
+                |_| {},
+                |else_| {
+                    else_
+                        .local_get(argc_ptr)
+                        .global_get(saved.runtime_argc)
+                        .i32_const(1)
+                        .binop(BinaryOp::I32Add);
+                    store_i32(else_, memory);
+                },
+            );
+        }
+
+        if let Some(extra_argc) = saved.extra_argc {
+            // $saved_extra_argc = *argc_ptr - argc;
+            body.local_get(argc_ptr);
+            load_i32(&mut body, memory)
+                .local_get(cx.argc)
+                .binop(BinaryOp::I32Sub)
+                .global_set(extra_argc);
+        }
+        if let Some(expansion) = &saved.expansion {
+            // $saved_argv_buf_size = size;
+            body.local_get(cx.size).global_set(expansion.argv_buf_size);
+        }
+        // *argv_buf_size_ptr = size;
+        body.local_get(argv_buf_size_ptr).local_get(cx.size);
+        store_i32(&mut body, memory);
+
+        body.i32_const(__WASI_ERRNO_SUCCESS);
         Ok(builder.finish(vec![argc_ptr, argv_buf_size_ptr], &mut module.funcs))
     }
 
+    /// Add the size of the runtime args to `size`, and count the runtime args passed
+    /// to the program in `$saved_runtime_argc`.
+    fn add_runtime_args_size(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &SizesGetContext,
+        saved: &SavedArgs,
+    ) {
+        let sealed = self.runtime_args != RuntimeArgs::Forward;
+        let original_argv = match saved.original_argv {
+            Some(original_argv) => original_argv,
+            None => {
+                // size += $saved_original_argv_buf_size;
+                builder.global_get(saved.original_argv_buf_size);
+                add_checked(builder, cx.size, cx.err, self.limit_errno);
+                if !sealed {
+                    // $saved_runtime_argc = argc - 1;
+                    builder
+                        .local_get(cx.argc)
+                        .i32_const(1)
+                        .binop(BinaryOp::I32Sub)
+                        .global_set(saved.runtime_argc);
+                }
+                return;
+            }
+        };
+
+        self.snapshot_runtime_args(builder, cx, saved, original_argv);
+        // size += strlen($saved_original_argv[0]) + 1;
+        builder.global_get(original_argv);
+        load_i32(builder, cx.memory).local_set(cx.arg);
+        add_string_size(builder, cx.memory, cx.arg, cx.size, cx.err);
+        if let Some(preset_set) = saved.preset_set {
+            self.select_preset_set(builder, cx, original_argv, preset_set);
+        }
+        if sealed {
+            return;
+        }
+
+        // for (index = 1; index < argc; index++) { ... }
+        builder.i32_const(1).local_set(cx.index);
+        builder.block(None, |done| {
+            let done_id = done.id();
+            done.loop_(None, |loop_| {
+                let loop_id = loop_.id();
+                loop_
+                    .local_get(cx.index)
+                    .local_get(cx.argc)
+                    .binop(BinaryOp::I32GeU)
+                    .br_if(done_id);
+                // char *arg = $saved_original_argv[index++];
+                loop_
+                    .global_get(original_argv)
+                    .local_get(cx.index)
+                    .const_(usize_to_wasm_i32(self.pointer_size()))
+                    .binop(BinaryOp::I32Mul)
+                    .binop(BinaryOp::I32Add);
+                load_i32(loop_, cx.memory).local_set(cx.arg);
+                loop_
+                    .local_get(cx.index)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(cx.index);
+                if let Some(is_denied_arg) = cx.is_denied_arg {
+                    // if (is_denied_arg(arg)) continue;
+                    loop_.local_get(cx.arg).call(is_denied_arg).br_if(loop_id);
+                }
+                if let Some(overridden) = saved.overridden {
+                    self.mark_overridden(loop_, cx, overridden);
+                }
+                // $saved_runtime_argc++;
+                loop_
+                    .global_get(saved.runtime_argc)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .global_set(saved.runtime_argc);
+                // size += strlen(arg) + 1;
+                add_string_size(loop_, cx.memory, cx.arg, cx.size, cx.err);
+                loop_.br(loop_id);
+            });
+        });
+    }
+
+    /// Set the bits of `$saved_overridden` for the keys the runtime arg has.
+    fn mark_overridden(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &SizesGetContext,
+        overridden: GlobalId,
+    ) {
+        for (index, key) in self.overridable_keys.iter().enumerate() {
+            // if (strcmp(arg, KEY) == 0 || strncmp(arg, "KEY=", LEN) == 0) {
+            //   $saved_overridden |= 1 << INDEX;
+            // }
+            string_matches_at(builder, cx.memory, key, cx.arg, true);
+            string_matches_at(
+                builder,
+                cx.memory,
+                &[key, b"=".as_slice()].concat(),
+                cx.arg,
+                false,
+            );
+            builder.binop(BinaryOp::I32Or).if_else(
+                None,
+                |then| {
+                    then.global_get(overridden)
+                        .i32_const(1 << index)
+                        .binop(BinaryOp::I32Or)
+                        .global_set(overridden);
+                },
+                |_| {},
+            );
+        }
+    }
+
+    /// `$saved_preset_set = PRESET_SET_FOR(basename($saved_original_argv[0]));`
+    fn select_preset_set(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &SizesGetContext,
+        original_argv: GlobalId,
+        preset_set: GlobalId,
+    ) {
+        builder.global_get(original_argv);
+        load_i32(builder, cx.memory).local_set(cx.arg);
+        find_basename(builder, cx.memory, cx.arg, cx.base, cx.err);
+        // $saved_preset_set = 0;
+        builder.i32_const(0).global_set(preset_set);
+        builder.block(None, |done| {
+            let done_id = done.id();
+            for (index, (name, _)) in self.multicall.iter().enumerate() {
+                // if (strcmp(base, NAME) == 0) { $saved_preset_set = INDEX; break; }
+                string_matches_at(done, cx.memory, name, cx.base, true);
+                done.if_else(
+                    None,
+                    |then| {
+                        then.const_(usize_to_wasm_i32(index + 1))
+                            .global_set(preset_set)
+                            .br(done_id);
+                    },
+                    |_| {},
+                );
+            }
+        });
+    }
+
+    /// Take a snapshot of the runtime args into a newly grown memory if not yet taken.
+    fn snapshot_runtime_args(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &SizesGetContext,
+        saved: &SavedArgs,
+        original_argv: GlobalId,
+    ) {
+        // if ($saved_original_argv == NULL) { ... }
+        builder
+            .global_get(original_argv)
+            .unop(UnaryOp::I32Eqz)
+            .if_else(
+                None,
+                |then| {
+                    then.global_get(saved.original_argv_buf_size)
+                        .local_set(cx.runtime_size);
+                    take_snapshot(
+                        then,
+                        cx.memory,
+                        cx.original_args_get,
+                        original_argv,
+                        cx.argc,
+                        cx.runtime_size,
+                        cx.err,
+                        self.limit_errno,
+                    );
+                },
+                |_| {},
+            );
+    }
+
     /// See the comment in `run` for the Code Shape.
     fn add_args_get(
         &self,
         module: &mut Module,
-        saved_original_argc: GlobalId,
+        saved: &SavedArgs,
+        is_denied_arg: Option<FunctionId>,
     ) -> anyhow::Result<FunctionId> {
         let original = get_import_function(module, &self.wasi_module_name, "args_get")?;
         let sig = module.types.get(module.funcs.get(original).ty()).clone();
         let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
 
-        let argv = module.locals.add(ValType::I32);
-        let argv_buf = module.locals.add(ValType::I32);
-        let err = module.locals.add(ValType::I32);
-        let extra_argv = module.locals.add(ValType::I32);
-
-        let memory = match module.memories.iter().next() {
-            Some(m) => m,
-            None => anyhow::bail!("no memory"),
+        let memory = first_memory(module)?;
+        let cx = ArgsGetContext {
+            memory,
+            is_denied_arg,
+            argv: module.locals.add(ValType::I32),
+            argv_buf: module.locals.add(ValType::I32),
+            err: module.locals.add(ValType::I32),
+            runtime_argv: module.locals.add(ValType::I32),
+            runtime_buf: module.locals.add(ValType::I32),
+            out: module.locals.add(ValType::I32),
+            index: module.locals.add(ValType::I32),
+            copied: module.locals.add(ValType::I32),
+            arg: module.locals.add(ValType::I32),
+            base: module.locals.add(ValType::I32),
+            byte: module.locals.add(ValType::I32),
+            expanded: module.locals.add(ValType::I32),
+            expander: None,
+        };
+        let cx = ArgsGetContext {
+            expander: saved.expansion.as_ref().map(|expansion| Expander {
+                memory,
+                env: expansion.env,
+                undefined: self.expand_env.unwrap_or_default(),
+                index: module.locals.add(ValType::I32),
+                p: cx.arg,
+                byte: cx.byte,
+            }),
+            ..cx
         };
 
         builder.name("wasi_preset_args.args_get".to_string());
-        let mut instr_builder = builder.func_body();
+        let mut body = builder.func_body();
 
-        // 1. Write argv[0], argv[1+args.len()...]
-        let instr_builder = instr_builder
-            .global_get(saved_original_argc)
-            .unop(UnaryOp::I32Eqz)
+        if let Some(expansion) = &saved.expansion {
+            // char *expanded = argv_buf + $saved_argv_buf_size - $saved_expanded_size;
+            body.local_get(cx.argv_buf)
+                .global_get(expansion.argv_buf_size)
+                .binop(BinaryOp::I32Add)
+                .global_get(expansion.expanded_size)
+                .binop(BinaryOp::I32Sub)
+                .local_set(cx.expanded);
+        }
+
+        if let (Some(n), FewerArgsPolicy::Error) =
+            (self.fewer_args_threshold(), self.fewer_args_policy)
+        {
+            // if ($saved_runtime_argc < N) return __WASI_ERRNO_INVAL;
+            body.global_get(saved.runtime_argc)
+                .const_(usize_to_wasm_i32(n))
+                .binop(BinaryOp::I32LtU)
+                .if_else(
+                    None,
+                    |then| {
+                        then.i32_const(__WASI_ERRNO_INVAL).return_();
+                    },
+                    |_| {},
+                );
+        }
+
+        // 1. Write argv[0]
+        // char **out = argv;
+        body.local_get(cx.argv).local_set(cx.out);
+        if self.fetches_runtime_args() {
+            body.global_get(saved.original_argc)
+                .unop(UnaryOp::I32Eqz)
+                .if_else(
+                    None,
+                    |then| self.write_static_argv0(then, &cx, &self.fallback_argv0()),
+                    |else_| {
+                        self.fetch_runtime_args(else_, &cx, saved, original);
+                        // *out = runtime_argv[0];
+                        self.write_runtime_arg(else_, &cx);
+                        self.apply_argv0_policy(else_, &cx);
+                    },
+                );
+        } else {
+            self.write_static_argv0(&mut body, &cx, &self.program_name_bytes());
+        }
+        // out++;
+        self.advance_out(&mut body, &cx);
+
+        // 2. Write the preset args and the runtime args except argv[0]
+        // i32 index = 1;
+        body.i32_const(1).local_set(cx.index);
+        if self.runtime_args == RuntimeArgs::Forward {
+            match self.placement {
+                Placement::Prepend => {
+                    self.write_preset_args_if_applied(&mut body, &cx, saved);
+                    self.copy_runtime_args(&mut body, &cx, saved, None);
+                }
+                Placement::Append => {
+                    self.copy_runtime_args(&mut body, &cx, saved, None);
+                    self.write_preset_args_if_applied(&mut body, &cx, saved);
+                }
+                Placement::After(n) => {
+                    self.copy_runtime_args(&mut body, &cx, saved, Some(n));
+                    self.write_preset_args_if_applied(&mut body, &cx, saved);
+                    self.copy_runtime_args(&mut body, &cx, saved, None);
+                }
+                Placement::Template => {
+                    self.write_template_part_if_applied(&mut body, &cx, saved, TemplatePart::Head);
+                    self.copy_runtime_args(&mut body, &cx, saved, None);
+                    self.write_template_part_if_applied(&mut body, &cx, saved, TemplatePart::Tail);
+                }
+            }
+        } else if self.placement == Placement::Template {
+            self.write_template_part_if_applied(&mut body, &cx, saved, TemplatePart::Head);
+            self.write_template_part_if_applied(&mut body, &cx, saved, TemplatePart::Tail);
+        } else {
+            self.write_preset_args_if_applied(&mut body, &cx, saved);
+        }
+
+        body.i32_const(__WASI_ERRNO_SUCCESS);
+        Ok(builder.finish(vec![cx.argv, cx.argv_buf], &mut module.funcs))
+    }
+
+    /// Write the given string at `argv_buf + PRESET_ARGS_SIZE()` and use it as argv[0].
+    fn write_static_argv0(&self, builder: &mut InstrSeqBuilder, cx: &ArgsGetContext, argv0: &[u8]) {
+        store_string_at(
+            builder,
+            cx.memory,
+            argv0,
+            cx.argv_buf,
+            self.preset_args_size(),
+        );
+        // *out = argv_buf + PRESET_ARGS_SIZE();
+        builder
+            .local_get(cx.out)
+            .local_get(cx.argv_buf)
+            .const_(usize_to_wasm_i32(self.preset_args_size()))
+            .binop(BinaryOp::I32Add);
+        store_i32(builder, cx.memory);
+    }
+
+    /// Set `runtime_argv` and `runtime_buf` up. Without the snapshot, let the original
+    /// `args_get` write the runtime args strings into `argv_buf`, and the pointers to them
+    /// into the end of `argv`, or the end of `argv_buf` if they are sealed.
+    fn fetch_runtime_args(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &ArgsGetContext,
+        saved: &SavedArgs,
+        original: FunctionId,
+    ) {
+        // char *runtime_buf = argv_buf + PRESET_ARGS_SIZE() + PROGRAM_NAME_SIZE();
+        builder
+            .local_get(cx.argv_buf)
+            .const_(self.argv_buf_size_value())
+            .binop(BinaryOp::I32Add)
+            .local_set(cx.runtime_buf);
+
+        if let Some(original_argv) = saved.original_argv {
+            // runtime_argv = $saved_original_argv;
+            builder.global_get(original_argv).local_set(cx.runtime_argv);
+            return;
+        }
+
+        match saved.extra_argc {
+            Some(extra_argc) => {
+                // The pointers are written in argv in ascending order, each of which is at or
+                // before the one of the same runtime arg, so unread ones are never overwritten.
+                // runtime_argv = argv + $saved_extra_argc;
+                builder
+                    .local_get(cx.argv)
+                    .global_get(extra_argc)
+                    .const_(usize_to_wasm_i32(self.pointer_size()))
+                    .binop(BinaryOp::I32Mul)
+                    .binop(BinaryOp::I32Add)
+                    .local_tee(cx.runtime_argv);
+            }
+            None => {
+                // runtime_argv = ALIGN_UP(runtime_buf + $saved_original_argv_buf_size);
+                builder
+                    .local_get(cx.runtime_buf)
+                    .global_get(saved.original_argv_buf_size)
+                    .binop(BinaryOp::I32Add)
+                    .const_(usize_to_wasm_i32(self.pointer_size() - 1))
+                    .binop(BinaryOp::I32Add)
+                    .i32_const(-(self.pointer_size() as i32))
+                    .binop(BinaryOp::I32And)
+                    .local_tee(cx.runtime_argv);
+            }
+        }
+        // err = $wasi_snapshot_preview1.args_get(runtime_argv, runtime_buf);
+        builder
+            .local_get(cx.runtime_buf)
+            .call(original)
+            .local_tee(cx.err)
             .if_else(
                 None,
                 |then| {
-                    store_string_at(
-                        then,
-                        memory.id(),
-                        self.program_name.to_string_lossy().as_bytes(),
-                        argv_buf,
-                        self.preset_args_size(),
-                    );
-                    then.local_get(argv)
-                        .local_get(argv_buf)
-                        .const_(usize_to_wasm_i32(self.preset_args_size()))
-                        .binop(BinaryOp::I32Add)
-                        .store(
-                            memory.id(),
-                            StoreKind::I32 { atomic: false },
-                            MemArg {
-                                align: 1,
-                                offset: 0,
-                            },
-                        );
-                },
-                |else_| {
-                    // 1. argv_buf ..< argv_buf + preset_buf_size: preset_buf
-                    // 2. argv_buf + preset_buf_size ..< argv_buf + preset_buf_size + original_buf_size: original_buf
-
-                    // write original argv[0] at argv[args.len()], and move it at argv[0]
-
-                    // char **extra_argv = argv + PRESET_ARGS_LEN();
-                    let else_ = else_
-                        .local_get(argv)
-                        .const_(usize_to_wasm_i32(self.args.len() * self.pointer_size()))
-                        .binop(BinaryOp::I32Add)
-                        .local_tee(extra_argv);
-
-                    // err = $wasi_snapshot_preview1.args_get(extra_argv, argv_buf + PRESET_ARGS_SIZE());
-                    let else_ = else_
-                        .local_get(argv_buf)
-                        .const_(self.argv_buf_size_value())
-                        .binop(BinaryOp::I32Add)
-                        .call(original)
-                        .local_tee(err);
-
-                    else_.unop(UnaryOp::I32Eqz).if_else(
-                        None,
-                        |then| {
-                            // argv[0] = extra_argv[0];
-                            then.local_get(argv)
-                                .local_get(extra_argv)
-                                .load(
-                                    memory.id(),
-                                    LoadKind::I32 { atomic: false },
-                                    MemArg {
-                                        align: 1,
-                                        offset: 0,
-                                    },
-                                )
-                                .store(
-                                    memory.id(),
-                                    StoreKind::I32 { atomic: false },
-                                    MemArg {
-                                        align: 1,
-                                        offset: 0,
-                                    },
-                                );
-                        },
-                        |_else| {
-                            _else.local_get(err).return_();
-                        },
-                    );
+                    then.local_get(cx.err).return_();
                 },
+                |_| {},
             );
+    }
 
-        // 2. Write argv[1..<1+args.len()]
-        let mut offset = 0;
-        for (i, arg) in self.args.iter().enumerate() {
-            store_string_at(instr_builder, memory.id(), arg, argv_buf, offset);
-            instr_builder
-                .local_get(argv)
-                .const_(usize_to_wasm_i32((i + 1) * self.pointer_size()))
-                .binop(BinaryOp::I32Add)
-                .local_get(argv_buf)
-                .const_(usize_to_wasm_i32(offset))
-                .binop(BinaryOp::I32Add)
-                .store(
-                    memory.id(),
-                    StoreKind::I32 { atomic: false },
-                    MemArg {
-                        align: 1,
-                        offset: 0,
+    /// `*out = runtime_argv[index]`, copying the string into `runtime_buf` if the runtime args
+    /// are in the snapshot.
+    fn write_runtime_arg(&self, builder: &mut InstrSeqBuilder, cx: &ArgsGetContext) {
+        // char *arg = runtime_argv[index];
+        builder
+            .local_get(cx.runtime_argv)
+            .local_get(cx.index)
+            .const_(usize_to_wasm_i32(self.pointer_size()))
+            .binop(BinaryOp::I32Mul)
+            .binop(BinaryOp::I32Add);
+        load_i32(builder, cx.memory).local_set(cx.arg);
+        self.write_runtime_arg_ptr(builder, cx);
+    }
+
+    /// `*out = arg`, copying the string into `runtime_buf` if the runtime args are in
+    /// the snapshot.
+    fn write_runtime_arg_ptr(&self, builder: &mut InstrSeqBuilder, cx: &ArgsGetContext) {
+        if !self.snapshots_runtime_args() {
+            // *out = arg;
+            builder.local_get(cx.out).local_get(cx.arg);
+            store_i32(builder, cx.memory);
+            return;
+        }
+        // *out = runtime_buf;
+        builder.local_get(cx.out).local_get(cx.runtime_buf);
+        store_i32(builder, cx.memory);
+        // runtime_buf = stpcpy(runtime_buf, arg) + 1;
+        copy_string(builder, cx.memory, cx.runtime_buf, cx.arg, cx.byte);
+    }
+
+    fn advance_out(&self, builder: &mut InstrSeqBuilder, cx: &ArgsGetContext) {
+        builder
+            .local_get(cx.out)
+            .const_(usize_to_wasm_i32(self.pointer_size()))
+            .binop(BinaryOp::I32Add)
+            .local_set(cx.out);
+    }
+
+    /// Push 1 if the preset args are applied for the runtime args, or 0 otherwise.
+    /// Returns false without pushing anything if they are always applied.
+    fn push_presets_applied(&self, builder: &mut InstrSeqBuilder, saved: &SavedArgs) -> bool {
+        let mut pushed = false;
+        if let (Some(n), FewerArgsPolicy::Skip) =
+            (self.fewer_args_threshold(), self.fewer_args_policy)
+        {
+            // $saved_runtime_argc >= N
+            builder
+                .global_get(saved.runtime_argc)
+                .const_(usize_to_wasm_i32(n))
+                .binop(BinaryOp::I32GeU);
+            pushed = true;
+        }
+        if self.default_only {
+            // $saved_runtime_argc == 0
+            builder.global_get(saved.runtime_argc).unop(UnaryOp::I32Eqz);
+            if pushed {
+                builder.binop(BinaryOp::I32And);
+            }
+            pushed = true;
+        }
+        pushed
+    }
+
+    /// `if (PRESETS_APPLIED()) { write_preset_args(); }`
+    fn write_preset_args_if_applied(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &ArgsGetContext,
+        saved: &SavedArgs,
+    ) {
+        self.write_template_part_if_applied(builder, cx, saved, TemplatePart::Whole);
+    }
+
+    /// `if (PRESETS_APPLIED()) { write_preset_args(); }` for the part of the template.
+    fn write_template_part_if_applied(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &ArgsGetContext,
+        saved: &SavedArgs,
+        part: TemplatePart,
+    ) {
+        if self.push_presets_applied(builder, saved) {
+            builder.if_else(
+                None,
+                |then| self.write_preset_args_of_selected_set(then, cx, saved, part),
+                |_| {},
+            );
+        } else {
+            self.write_preset_args_of_selected_set(builder, cx, saved, part);
+        }
+    }
+
+    fn write_preset_args_of_selected_set(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &ArgsGetContext,
+        saved: &SavedArgs,
+        part: TemplatePart,
+    ) {
+        self.dispatch_preset_set(builder, saved, None, &|builder, args| {
+            let (args, offset) = self.template_part(args, part);
+            self.write_preset_args(builder, cx, saved, args, offset)
+        });
+        if let (Some(n), Some(separator)) = (self.separator_threshold(), &self.separator) {
+            // if ($saved_runtime_argc > N) { *out++ = SEPARATOR; }
+            builder
+                .global_get(saved.runtime_argc)
+                .const_(usize_to_wasm_i32(n))
+                .binop(BinaryOp::I32GtU)
+                .if_else(
+                    None,
+                    |then| {
+                        let offset = self.separator_offset();
+                        store_string_at(then, cx.memory, separator, cx.argv_buf, offset);
+                        then.local_get(cx.out)
+                            .local_get(cx.argv_buf)
+                            .const_(usize_to_wasm_i32(offset))
+                            .binop(BinaryOp::I32Add);
+                        store_i32(then, cx.memory);
+                        self.advance_out(then, cx);
                     },
+                    |_| {},
                 );
+        }
+    }
+
+    /// Write the preset args data at `argv_buf + offset`, and pointers to them at `out`,
+    /// then advance `out`.
+    fn write_preset_args(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &ArgsGetContext,
+        saved: &SavedArgs,
+        args: &[Vec<u8>],
+        mut offset: usize,
+    ) {
+        for arg in args.iter() {
+            let write = |builder: &mut InstrSeqBuilder| {
+                builder.local_get(cx.out);
+                match (self.preset_arg(arg), &cx.expander) {
+                    (PresetArg::Expanded(segments), Some(expander)) => {
+                        // *out = expanded;
+                        builder.local_get(cx.expanded);
+                        store_i32(builder, cx.memory);
+                        // expanded = stpcpy(expanded, EXPANDED(i)) + 1;
+                        expander.write(builder, &segments, cx.expanded);
+                    }
+                    (PresetArg::Static(arg), _) => {
+                        // *out = argv_buf + PRESET_ARGS_OFFSET(i);
+                        builder
+                            .local_get(cx.argv_buf)
+                            .const_(usize_to_wasm_i32(offset))
+                            .binop(BinaryOp::I32Add);
+                        store_i32(builder, cx.memory);
+                        store_string_at(builder, cx.memory, &arg, cx.argv_buf, offset);
+                    }
+                    (PresetArg::Expanded(_), None) => unreachable!(),
+                }
+                // out++;
+                self.advance_out(builder, cx);
+            };
+            match (self.override_key_index(arg), saved.overridden) {
+                (Some(key), Some(overridden)) => {
+                    // if (!($saved_overridden & (1 << KEY))) { ... }
+                    builder
+                        .global_get(overridden)
+                        .i32_const(1 << key)
+                        .binop(BinaryOp::I32And)
+                        .if_else(None, |_| {}, write);
+                }
+                _ => write(builder),
+            }
             offset += arg.len() + 1;
         }
+    }
 
-        instr_builder.i32_const(__WASI_ERRNO_SUCCESS);
-        Ok(builder.finish(vec![argv, argv_buf], &mut module.funcs))
+    /// Emit code specialized for the preset args set selected by argv[0].
+    /// `f` is called for each set with a block of type `ty`.
+    fn dispatch_preset_set(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        saved: &SavedArgs,
+        ty: Option<ValType>,
+        f: &dyn Fn(&mut InstrSeqBuilder, &[Vec<u8>]),
+    ) {
+        let preset_set = match saved.preset_set {
+            Some(preset_set) => preset_set,
+            None => return f(builder, &self.args),
+        };
+        fn dispatch_from(
+            builder: &mut InstrSeqBuilder,
+            preset_set: GlobalId,
+            sets: &[&[Vec<u8>]],
+            index: usize,
+            ty: Option<ValType>,
+            f: &dyn Fn(&mut InstrSeqBuilder, &[Vec<u8>]),
+        ) {
+            if index == sets.len() {
+                return f(builder, sets[0]);
+            }
+            // if ($saved_preset_set == INDEX) { ... } else { ... }
+            builder
+                .global_get(preset_set)
+                .const_(usize_to_wasm_i32(index))
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    ty,
+                    |then| f(then, sets[index]),
+                    |else_| dispatch_from(else_, preset_set, sets, index + 1, ty, f),
+                );
+        }
+        dispatch_from(builder, preset_set, &self.preset_sets(), 1, ty, f);
+    }
+
+    /// Copy the runtime args from `runtime_argv[index]` to `out` until all of them or `limit`
+    /// of them are copied, skipping the denied ones.
+    fn copy_runtime_args(
+        &self,
+        builder: &mut InstrSeqBuilder,
+        cx: &ArgsGetContext,
+        saved: &SavedArgs,
+        limit: Option<usize>,
+    ) {
+        // i32 copied = 0;
+        builder.i32_const(0).local_set(cx.copied);
+        builder.block(None, |done| {
+            let done_id = done.id();
+            done.loop_(None, |loop_| {
+                let loop_id = loop_.id();
+                // if (index >= $saved_original_argc) break;
+                loop_
+                    .local_get(cx.index)
+                    .global_get(saved.original_argc)
+                    .binop(BinaryOp::I32GeU)
+                    .br_if(done_id);
+                if let Some(limit) = limit {
+                    // if (copied >= LIMIT) break;
+                    loop_
+                        .local_get(cx.copied)
+                        .const_(usize_to_wasm_i32(limit))
+                        .binop(BinaryOp::I32GeU)
+                        .br_if(done_id);
+                }
+                // char *arg = runtime_argv[index++];
+                loop_
+                    .local_get(cx.runtime_argv)
+                    .local_get(cx.index)
+                    .const_(usize_to_wasm_i32(self.pointer_size()))
+                    .binop(BinaryOp::I32Mul)
+                    .binop(BinaryOp::I32Add);
+                load_i32(loop_, cx.memory).local_set(cx.arg);
+                loop_
+                    .local_get(cx.index)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(cx.index);
+                if let Some(is_denied_arg) = cx.is_denied_arg {
+                    // if (is_denied_arg(arg)) continue;
+                    loop_.local_get(cx.arg).call(is_denied_arg).br_if(loop_id);
+                }
+                // *out++ = arg;
+                self.write_runtime_arg_ptr(loop_, cx);
+                self.advance_out(loop_, cx);
+                // copied++;
+                loop_
+                    .local_get(cx.copied)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(cx.copied);
+                loop_.br(loop_id);
+            });
+        });
+    }
+
+    /// Rewrite argv[0] given by the runtime according to the [`Argv0Policy`].
+    fn apply_argv0_policy(&self, builder: &mut InstrSeqBuilder, cx: &ArgsGetContext) {
+        match self.argv0_policy {
+            Argv0Policy::KeepRuntime | Argv0Policy::Fallback => {}
+            Argv0Policy::AlwaysOverride => {
+                // The runtime argv[0] bytes are left unused in the buffer, and the program
+                // name is written at the reserved space after the preset args.
+                self.write_static_argv0(builder, cx, &self.program_name_bytes());
+            }
+            Argv0Policy::Basename => {
+                // Point into the middle of the runtime argv[0] instead of copying it.
+                // argv[0] = basename(argv[0]);
+                builder.local_get(cx.argv);
+                load_i32(builder, cx.memory).local_set(cx.arg);
+                find_basename(builder, cx.memory, cx.arg, cx.base, cx.byte);
+                builder.local_get(cx.argv).local_get(cx.base);
+                store_i32(builder, cx.memory);
+            }
+        }
     }
 }
 
+/// Locals and functions shared among the pieces of `$wasi_preset_args.args_get`.
+struct ArgsGetContext {
+    memory: MemoryId,
+    is_denied_arg: Option<FunctionId>,
+    // Arguments
+    argv: LocalId,
+    argv_buf: LocalId,
+    // Locals
+    err: LocalId,
+    runtime_argv: LocalId,
+    runtime_buf: LocalId,
+    out: LocalId,
+    index: LocalId,
+    copied: LocalId,
+    arg: LocalId,
+    base: LocalId,
+    byte: LocalId,
+    /// Cursor to write the expanded preset args
+    expanded: LocalId,
+    expander: Option<Expander>,
+}
+
+/// Locals and functions shared among the pieces of `$wasi_preset_args.args_sizes_get`.
+struct SizesGetContext {
+    memory: MemoryId,
+    original_args_get: FunctionId,
+    is_denied_arg: Option<FunctionId>,
+    // Locals
+    err: LocalId,
+    argc: LocalId,
+    size: LocalId,
+    index: LocalId,
+    arg: LocalId,
+    base: LocalId,
+    runtime_size: LocalId,
+    expanded_size: LocalId,
+    expander: Option<Expander>,
+}
+
+/// Globals shared between `$wasi_preset_args.args_sizes_get` and `$wasi_preset_args.args_get`.
+struct SavedArgs {
+    /// argc reported by the original `args_sizes_get`
+    original_argc: GlobalId,
+    /// argv_buf_size reported by the original `args_sizes_get`
+    original_argv_buf_size: GlobalId,
+    /// The number of the runtime args (except argv[0]) passed to the program
+    runtime_argc: GlobalId,
+    /// Pointer to the runtime args snapshot, if taken
+    original_argv: Option<GlobalId>,
+    /// The number of the args added to the runtime ones, if the original `args_get` writes
+    /// the pointers to the runtime args at the end of argv
+    extra_argc: Option<GlobalId>,
+    /// Index of the preset args set selected by argv[0] (0 for the default set)
+    preset_set: Option<GlobalId>,
+    /// Bit set of the overridable keys given in the runtime args
+    overridden: Option<GlobalId>,
+    /// Present if any preset arg has `${NAME}` to expand
+    expansion: Option<SavedExpansion>,
+}
+
+/// Globals for the preset args expanded at runtime.
+struct SavedExpansion {
+    env: EnvSnapshot,
+    /// The size of the expanded preset args, placed at the end of argv_buf
+    expanded_size: GlobalId,
+    /// argv_buf_size reported to the program
+    argv_buf_size: GlobalId,
+}
+
+/// The part of the preset args set split by `$@` of [`Placement::Template`].
+#[derive(Clone, Copy)]
+enum TemplatePart {
+    /// All of them, used when the placement is not [`Placement::Template`]
+    Whole,
+    /// The ones before `$@`, or all of them if the set has no `$@`
+    Head,
+    /// The ones after `$@`
+    Tail,
+}
+
+/// A preset arg with `${NAME}`s parsed.
+enum PresetArg {
+    Static(Vec<u8>),
+    Expanded(Vec<Segment>),
+}
+
 const __WASI_ERRNO_SUCCESS: i32 = 0;
+const __WASI_ERRNO_2BIG: i32 = 1;
+const __WASI_ERRNO_BADF: i32 = 8;
+const __WASI_ERRNO_INVAL: i32 = 28;
+const __WASI_ERRNO_NAMETOOLONG: i32 = 37;
+const __WASI_ERRNO_NFILE: i32 = 41;
+const __WASI_ERRNO_NOENT: i32 = 44;
+const __WASI_ERRNO_NOMEM: i32 = 48;
+const __WASI_ERRNO_NOTDIR: i32 = 54;
+const __WASI_ERRNO_OVERFLOW: i32 = 61;
+const __WASI_ERRNO_ROFS: i32 = 69;
+
+const __WASI_PREOPENTYPE_DIR: i32 = 0;
+/// sizeof(__wasi_prestat_t)
+const PRESTAT_SIZE: i32 = 8;
+/// offsetof(__wasi_prestat_t, u.dir.pr_name_len)
+const PRESTAT_NAME_LEN_OFFSET: i32 = 4;
+
+const WASM_PAGE_SIZE: i32 = 0x10000;
+
+/// `$@` in the template of [`Placement::Template`]
+fn is_splice_marker(arg: &[u8]) -> bool {
+    arg == b"$@"
+}
+
+fn args_to_strings(args: &[Vec<u8>]) -> Vec<String> {
+    args.iter()
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
+fn first_memory(module: &Module) -> anyhow::Result<MemoryId> {
+    match module.memories.iter().next() {
+        Some(m) => Ok(m.id()),
+        None => anyhow::bail!("no memory"),
+    }
+}
 
 fn get_import_function(m: &Module, module: &str, name: &str) -> anyhow::Result<FunctionId> {
     let original = match m.imports.find(module, name) {
@@ -470,6 +1945,16 @@ fn get_import_function(m: &Module, module: &str, name: &str) -> anyhow::Result<F
     Ok(original)
 }
 
+/// Get the WASI function imported by the module, or import it if not yet.
+/// All the functions imported by this are `(i32, i32) -> i32`.
+fn get_or_add_import_function(m: &mut Module, module: &str, name: &str) -> FunctionId {
+    if let Ok(f) = get_import_function(m, module, name) {
+        return f;
+    }
+    let ty = m.types.add(&[ValType::I32, ValType::I32], &[ValType::I32]);
+    m.add_import_func(module, name, ty).0
+}
+
 fn usize_to_wasm_i32(x: usize) -> Value {
     Value::I32(i32::from_le_bytes((x as u32).to_le_bytes()))
 }
@@ -481,9 +1966,18 @@ fn store_string_at(
     base: LocalId,
     offset: usize,
 ) {
-    let mut written = 0;
     // Put '\0' at the end of the string
-    let s = [s, &[0]].concat();
+    store_bytes_at(builder, memory, &[s, &[0]].concat(), base, offset)
+}
+
+fn store_bytes_at(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    s: &[u8],
+    base: LocalId,
+    offset: usize,
+) {
+    let mut written = 0;
     for chunk_size in [8, 4, 2, 1] {
         let chunk_count = (s.len() - written) / chunk_size;
         for _ in 0..chunk_count {
@@ -524,3 +2018,319 @@ fn store_string_at(
         }
     }
 }
+
+/// Push 1 if the NUL-terminated string at `base` is equal to `s` (or starts with `s` if not
+/// `exact`), or 0 otherwise. Bytes after the first mismatch are not read.
+fn string_matches_at(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    s: &[u8],
+    base: LocalId,
+    exact: bool,
+) {
+    builder.block(ValType::I32, |block| {
+        let block_id = block.id();
+        let expected = s.iter().copied().chain(exact.then_some(0));
+        for (offset, byte) in expected.enumerate() {
+            // if (base[offset] != byte) return 0;
+            block.i32_const(0).local_get(base);
+            load_u8(block, memory, offset as u32)
+                .i32_const(byte as i32)
+                .binop(BinaryOp::I32Ne)
+                .br_if(block_id)
+                .drop();
+        }
+        block.i32_const(1);
+    });
+}
+
+/// Take a snapshot of a string vector (args or environ) by `get` into a newly grown memory,
+/// and save the pointer to the vector in `snapshot`. `count` and `size` must hold the
+/// sizes reported by the corresponding sizes function. `size` and `err` are clobbered.
+///
+/// ```c
+/// if (count > UINT32_MAX / sizeof(char *) ||
+///     __builtin_add_overflow(size, count * sizeof(char *), &size)) {
+///   return OVERFLOW_ERRNO;
+/// }
+/// i32 base = memory.grow(size / 0x10000 + (size % 0x10000 != 0));
+/// if (base == -1) {
+///   return __WASI_ERRNO_NOMEM;
+/// }
+/// snapshot = base * 0x10000;
+/// // Some runtimes reject the get function with no entries.
+/// if (count != 0) {
+///   err = get(snapshot, snapshot + count);
+///   if (err != __WASI_ERRNO_SUCCESS) {
+///     snapshot = NULL; // Take it again next time.
+///     return err;
+///   }
+/// }
+/// ```
+#[allow(clippy::too_many_arguments)]
+fn take_snapshot(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    get: FunctionId,
+    snapshot: GlobalId,
+    count: LocalId,
+    size: LocalId,
+    err: LocalId,
+    overflow_errno: u16,
+) {
+    builder
+        .local_get(count)
+        .const_(usize_to_wasm_i32(u32::MAX as usize / 4))
+        .binop(BinaryOp::I32GtU)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(overflow_errno.into()).return_();
+            },
+            |_| {},
+        );
+    builder
+        .local_get(count)
+        .i32_const(4)
+        .binop(BinaryOp::I32Mul);
+    add_checked(builder, size, err, overflow_errno);
+    builder
+        .local_get(size)
+        .i32_const(16)
+        .binop(BinaryOp::I32ShrU)
+        .local_get(size)
+        .i32_const(WASM_PAGE_SIZE - 1)
+        .binop(BinaryOp::I32And)
+        .i32_const(0)
+        .binop(BinaryOp::I32Ne)
+        .binop(BinaryOp::I32Add)
+        .memory_grow(memory)
+        .local_tee(err)
+        .i32_const(-1)
+        .binop(BinaryOp::I32Eq)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(__WASI_ERRNO_NOMEM).return_();
+            },
+            |_| {},
+        );
+    builder
+        .local_get(err)
+        .i32_const(16)
+        .binop(BinaryOp::I32Shl)
+        .global_set(snapshot);
+    builder.local_get(count).if_else(
+        None,
+        |then| {
+            then.global_get(snapshot)
+                .global_get(snapshot)
+                .local_get(count)
+                .i32_const(4)
+                .binop(BinaryOp::I32Mul)
+                .binop(BinaryOp::I32Add)
+                .call(get)
+                .local_tee(err)
+                .if_else(
+                    None,
+                    |then| {
+                        then.i32_const(0).global_set(snapshot);
+                        then.local_get(err).return_();
+                    },
+                    |_| {},
+                );
+        },
+        |_| {},
+    );
+}
+
+/// `acc += value` with the value on the stack, returning `errno` from the function
+/// on unsigned overflow. `tmp` is clobbered.
+fn add_checked(builder: &mut InstrSeqBuilder, acc: LocalId, tmp: LocalId, errno: u16) {
+    // if ((acc += value) < value) return errno;
+    builder
+        .local_tee(tmp)
+        .local_get(acc)
+        .binop(BinaryOp::I32Add)
+        .local_tee(acc)
+        .local_get(tmp)
+        .binop(BinaryOp::I32LtU)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(errno.into()).return_();
+            },
+            |_| {},
+        );
+}
+
+/// `base = basename(p)`, pointing into the middle of the NUL-terminated string at `p`.
+/// `p` is clobbered.
+fn find_basename(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    p: LocalId,
+    base: LocalId,
+    byte: LocalId,
+) {
+    // char *base = p;
+    // while (true) {
+    //   char c = *p;
+    //   if (c == '\0') break;
+    //   p++;
+    //   if (c == '/' || c == '\\') base = p;
+    // }
+    builder.local_get(p).local_set(base);
+    builder.block(None, |done| {
+        let done_id = done.id();
+        done.loop_(None, |loop_| {
+            let loop_id = loop_.id();
+            loop_.local_get(p);
+            load_u8(loop_, memory, 0)
+                .local_tee(byte)
+                .unop(UnaryOp::I32Eqz)
+                .br_if(done_id);
+            loop_
+                .local_get(p)
+                .i32_const(1)
+                .binop(BinaryOp::I32Add)
+                .local_set(p);
+            loop_
+                .local_get(byte)
+                .i32_const(b'/' as i32)
+                .binop(BinaryOp::I32Eq)
+                .local_get(byte)
+                .i32_const(b'\\' as i32)
+                .binop(BinaryOp::I32Eq)
+                .binop(BinaryOp::I32Or)
+                .if_else(
+                    None,
+                    |then| {
+                        then.local_get(p).local_set(base);
+                    },
+                    |_| {},
+                );
+            loop_.br(loop_id);
+        });
+    });
+}
+
+/// `size += strlen(s) + 1`. `s` is clobbered.
+fn add_string_size(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    s: LocalId,
+    size: LocalId,
+    byte: LocalId,
+) {
+    // do { byte = *s++; size++; } while (byte != '\0');
+    builder.loop_(None, |loop_| {
+        let loop_id = loop_.id();
+        loop_.local_get(s);
+        load_u8(loop_, memory, 0).local_set(byte);
+        loop_
+            .local_get(s)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .local_set(s);
+        loop_
+            .local_get(size)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .local_set(size);
+        loop_.local_get(byte).br_if(loop_id);
+    });
+}
+
+/// Copy the NUL-terminated string at `src` to `dst`, and advance `dst` past the NUL.
+/// `src` is clobbered.
+fn copy_string(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    dst: LocalId,
+    src: LocalId,
+    byte: LocalId,
+) {
+    // do { byte = *src++; *dst++ = byte; } while (byte != '\0');
+    builder.loop_(None, |loop_| {
+        let loop_id = loop_.id();
+        loop_.local_get(dst).local_get(src);
+        load_u8(loop_, memory, 0).local_tee(byte).store(
+            memory,
+            StoreKind::I32_8 { atomic: false },
+            MemArg {
+                align: 1,
+                offset: 0,
+            },
+        );
+        loop_
+            .local_get(src)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .local_set(src);
+        loop_
+            .local_get(dst)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .local_set(dst);
+        loop_.local_get(byte).br_if(loop_id);
+    });
+}
+
+/// `*(u8 *)(addr + offset)` with the address on the stack
+fn load_u8<'a, 'b>(
+    builder: &'a mut InstrSeqBuilder<'b>,
+    memory: MemoryId,
+    offset: u32,
+) -> &'a mut InstrSeqBuilder<'b> {
+    builder.load(
+        memory,
+        LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        },
+        MemArg { align: 1, offset },
+    )
+}
+
+/// `*(i32 *)addr` with the address on the stack
+fn load_i32<'a, 'b>(
+    builder: &'a mut InstrSeqBuilder<'b>,
+    memory: MemoryId,
+) -> &'a mut InstrSeqBuilder<'b> {
+    builder.load(
+        memory,
+        LoadKind::I32 { atomic: false },
+        MemArg {
+            align: 1,
+            offset: 0,
+        },
+    )
+}
+
+/// `*(i32 *)addr = value` with the address and the value on the stack
+fn store_i32<'a, 'b>(
+    builder: &'a mut InstrSeqBuilder<'b>,
+    memory: MemoryId,
+) -> &'a mut InstrSeqBuilder<'b> {
+    builder.store(
+        memory,
+        StoreKind::I32 { atomic: false },
+        MemArg {
+            align: 1,
+            offset: 0,
+        },
+    )
+}
+
+/// `*(i64 *)(addr + offset) = value` with the address and the value on the stack
+fn store_i64<'a, 'b>(
+    builder: &'a mut InstrSeqBuilder<'b>,
+    memory: MemoryId,
+    offset: u32,
+) -> &'a mut InstrSeqBuilder<'b> {
+    builder.store(
+        memory,
+        StoreKind::I64 { atomic: false },
+        MemArg { align: 1, offset },
+    )
+}
@@ -0,0 +1,232 @@
+//! Presetting the time read by a WASI program from the clocks.
+
+use walrus::{
+    ir::{BinaryOp, Value},
+    FunctionBuilder, FunctionId, GlobalId, InitExpr, Module, ValType,
+};
+
+use crate::{first_memory, store_i64, Interposer};
+
+/// WASI `__wasi_clockid_t`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockId {
+    Realtime,
+    Monotonic,
+    ProcessCputime,
+    ThreadCputime,
+}
+
+impl ClockId {
+    fn to_wasi(self) -> i32 {
+        match self {
+            ClockId::Realtime => 0,
+            ClockId::Monotonic => 1,
+            ClockId::ProcessCputime => 2,
+            ClockId::ThreadCputime => 3,
+        }
+    }
+}
+
+impl std::str::FromStr for ClockId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "realtime" => Ok(ClockId::Realtime),
+            "monotonic" => Ok(ClockId::Monotonic),
+            "process_cputime" => Ok(ClockId::ProcessCputime),
+            "thread_cputime" => Ok(ClockId::ThreadCputime),
+            _ => anyhow::bail!(
+                "unknown clock: {} (expected realtime, monotonic, process_cputime or thread_cputime)",
+                s
+            ),
+        }
+    }
+}
+
+/// A clock returning `time + n * step` nanoseconds on the n-th read (counted from 0)
+#[derive(Debug, Clone, Copy)]
+struct FrozenClock {
+    id: ClockId,
+    time: u64,
+    step: u64,
+}
+
+impl FrozenClock {
+    /// The resolution reported by `clock_res_get`
+    fn resolution(&self) -> u64 {
+        self.step.max(1)
+    }
+}
+
+pub struct PresetClock {
+    clocks: Vec<FrozenClock>,
+    wasi_module_name: String,
+}
+
+impl Default for PresetClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PresetClock {
+    pub fn new() -> Self {
+        Self {
+            clocks: vec![],
+            wasi_module_name: "wasi_snapshot_preview1".to_string(),
+        }
+    }
+
+    /// Make the clock `id` return `time` nanoseconds on the first read, advancing by `step`
+    /// nanoseconds on each read. A `step` of 0 freezes the clock.
+    ///
+    /// `clock_res_get` of the clock returns `step`, or 1 if it's 0. The other clocks are
+    /// left as is.
+    pub fn freeze(&mut self, id: ClockId, time: u64, step: u64) -> &mut Self {
+        self.clocks.retain(|clock| clock.id != id);
+        self.clocks.push(FrozenClock { id, time, step });
+        self
+    }
+
+    /// Instrument the input Wasm so that it reads the preset time from the frozen clocks.
+    ///
+    /// ## Code Shape
+    ///
+    /// Like [`crate::PresetArgs::run`], this adds proxies of `clock_time_get` and
+    /// `clock_res_get` (if imported). The next time of each frozen clock is kept in a
+    /// mutable global:
+    ///
+    /// ```c
+    /// u64 $next_time(i) = TIME(i);
+    ///
+    /// i32 $wasi_preset_clock.clock_time_get(i32 id, u64 precision, u64 *time) {
+    ///   if (id == ID(i)) {
+    ///     *time = $next_time(i);
+    ///     $next_time(i) += STEP(i);
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   ...
+    ///   return $wasi_snapshot_preview1.clock_time_get(id, precision, time);
+    /// }
+    /// i32 $wasi_preset_clock.clock_res_get(i32 id, u64 *resolution) {
+    ///   if (id == ID(i)) {
+    ///     *resolution = RESOLUTION(i);
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   ...
+    ///   return $wasi_snapshot_preview1.clock_res_get(id, resolution);
+    /// }
+    /// ```
+    ///
+    /// ## Limitations
+    ///
+    /// The time wraps around silently when it overflows u64.
+    pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
+        let mut interposer = Interposer::new("wasi_preset_clock");
+        interposer
+            .interpose(
+                &self.wasi_module_name,
+                "clock_time_get",
+                |module, original| self.add_clock_time_get(module, original),
+            )
+            // `clock_res_get` is imported only by the programs calling `clock_getres`.
+            .interpose_if_imported(
+                &self.wasi_module_name,
+                "clock_res_get",
+                |module, original| self.add_clock_res_get(module, original),
+            );
+        interposer.run(module)
+    }
+
+    fn add_clock_time_get(
+        &self,
+        module: &mut Module,
+        original: FunctionId,
+    ) -> anyhow::Result<FunctionId> {
+        let memory = first_memory(module)?;
+        let next_times = self
+            .clocks
+            .iter()
+            .map(|clock| {
+                module.globals.add_local(
+                    ValType::I64,
+                    true,
+                    InitExpr::Value(Value::I64(clock.time as i64)),
+                )
+            })
+            .collect::<Vec<GlobalId>>();
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_clock.clock_time_get".to_string());
+        // Arguments
+        let id = module.locals.add(ValType::I32);
+        let precision = module.locals.add(ValType::I64);
+        let time = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        for (clock, next_time) in self.clocks.iter().zip(next_times) {
+            body.local_get(id)
+                .i32_const(clock.id.to_wasi())
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        // *time = $next_time;
+                        then.local_get(time).global_get(next_time);
+                        store_i64(then, memory, 0);
+                        // $next_time += STEP;
+                        if clock.step != 0 {
+                            then.global_get(next_time)
+                                .i64_const(clock.step as i64)
+                                .binop(BinaryOp::I64Add)
+                                .global_set(next_time);
+                        }
+                        then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                    },
+                    |_| {},
+                );
+        }
+        // return $wasi_snapshot_preview1.clock_time_get(id, precision, time);
+        body.local_get(id)
+            .local_get(precision)
+            .local_get(time)
+            .call(original);
+        Ok(builder.finish(vec![id, precision, time], &mut module.funcs))
+    }
+
+    fn add_clock_res_get(
+        &self,
+        module: &mut Module,
+        original: FunctionId,
+    ) -> anyhow::Result<FunctionId> {
+        let memory = first_memory(module)?;
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_clock.clock_res_get".to_string());
+        // Arguments
+        let id = module.locals.add(ValType::I32);
+        let resolution = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        for clock in &self.clocks {
+            body.local_get(id)
+                .i32_const(clock.id.to_wasi())
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        // *resolution = RESOLUTION;
+                        then.local_get(resolution)
+                            .i64_const(clock.resolution() as i64);
+                        store_i64(then, memory, 0);
+                        then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                    },
+                    |_| {},
+                );
+        }
+        // return $wasi_snapshot_preview1.clock_res_get(id, resolution);
+        body.local_get(id).local_get(resolution).call(original);
+        Ok(builder.finish(vec![id, resolution], &mut module.funcs))
+    }
+}
@@ -0,0 +1,593 @@
+//! Presetting the environment variables of a WASI program in the same way as the args.
+
+use std::ffi::OsString;
+
+use walrus::{
+    ir::{BinaryOp, Value},
+    FunctionBuilder, FunctionId, GlobalId, InitExpr, InstrSeqBuilder, Module, ValType,
+};
+
+use crate::{
+    add_checked, add_string_size, copy_string, expand_env::EnvSnapshot, first_memory,
+    get_import_function, load_i32, store_i32, store_string_at, string_matches_at,
+    usize_to_wasm_i32, ArgPattern, Interposer,
+};
+
+/// What to do when the runtime gives an environment variable with the same key as a preset one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnvPrecedence {
+    /// Drop the runtime one, so that the program sees only the preset one
+    #[default]
+    Preset,
+    /// Drop the preset one, so that it works as a default value
+    Host,
+    /// Make `environ_sizes_get` fail with `__WASI_ERRNO_INVAL`
+    Error,
+}
+
+impl std::str::FromStr for EnvPrecedence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "preset" => Ok(EnvPrecedence::Preset),
+            "host" => Ok(EnvPrecedence::Host),
+            "error" => Ok(EnvPrecedence::Error),
+            _ => anyhow::bail!(
+                "unknown env precedence: {} (expected preset, host or error)",
+                s
+            ),
+        }
+    }
+}
+
+struct PresetVar {
+    key: Vec<u8>,
+    /// `KEY=VALUE`
+    var: Vec<u8>,
+    precedence: EnvPrecedence,
+}
+
+pub struct PresetEnv {
+    vars: Vec<PresetVar>,
+    allowed_host_vars: Vec<ArgPattern>,
+    denied_host_vars: Vec<ArgPattern>,
+    wasi_module_name: String,
+}
+
+impl PresetEnv {
+    pub fn new(vars: Vec<(OsString, OsString)>) -> Self {
+        let vars = vars
+            .into_iter()
+            .map(|(key, value)| {
+                let key = key.to_string_lossy().as_bytes().to_vec();
+                let mut var = key.clone();
+                var.push(b'=');
+                var.extend_from_slice(value.to_string_lossy().as_bytes());
+                PresetVar {
+                    key,
+                    var,
+                    precedence: EnvPrecedence::default(),
+                }
+            })
+            .collect();
+        Self {
+            vars,
+            allowed_host_vars: vec![],
+            denied_host_vars: vec![],
+            wasi_module_name: "wasi_snapshot_preview1".to_string(),
+        }
+    }
+
+    /// Set what to do when the runtime also gives the variable `key`. At most 32 variables
+    /// can have [`EnvPrecedence::Host`]. Fails if `key` is not preset.
+    pub fn precedence(
+        &mut self,
+        key: OsString,
+        precedence: EnvPrecedence,
+    ) -> anyhow::Result<&mut Self> {
+        let key_bytes = key.to_string_lossy().as_bytes().to_vec();
+        let mut found = false;
+        for var in self.vars.iter_mut().filter(|var| var.key == key_bytes) {
+            var.precedence = precedence;
+            found = true;
+        }
+        if !found {
+            anyhow::bail!("env precedence for a variable not preset: {:?}", key);
+        }
+        Ok(self)
+    }
+
+    /// Pass only the environment variables given by the runtime with the keys matching any of
+    /// the allowed patterns. Can be called multiple times. The preset ones are not filtered.
+    pub fn allow_host_var(&mut self, pattern: ArgPattern) -> &mut Self {
+        self.allowed_host_vars.push(pattern);
+        self
+    }
+
+    /// Drop the environment variables given by the runtime with the keys matching the pattern,
+    /// e.g. `AWS_*`. Can be called multiple times. The preset ones are not filtered.
+    pub fn deny_host_var(&mut self, pattern: ArgPattern) -> &mut Self {
+        self.denied_host_vars.push(pattern);
+        self
+    }
+
+    /// Instrument the input Wasm so that it sees the preset environment variables in addition
+    /// to the ones given by the runtime.
+    ///
+    /// ## Code Shape
+    ///
+    /// Like [`crate::PresetArgs::run`], this adds two proxies of the environ_* functions.
+    /// The `KEY=VALUE` strings are encoded in const instruction's immediates and written at the
+    /// front of environ_buf, followed by the runtime ones.
+    ///
+    /// `environ_sizes_get` has to report the exact sizes, which depend on the runtime ones
+    /// dropped for the preset ones, so it takes a snapshot of the runtime ones in the grown
+    /// memory (as [`crate::PresetArgs::expand_env`] does) once, on the first call. Then the
+    /// entries rejected by the generated `$wasi_preset_env.is_host_var_allowed` are dropped
+    /// from the snapshot: the ones with the keys of the [`EnvPrecedence::Preset`] variables
+    /// (the default), and the ones filtered by [`PresetEnv::allow_host_var`] and
+    /// [`PresetEnv::deny_host_var`]. `environ_get` copies the remaining entries from the
+    /// snapshot:
+    ///
+    /// ```c
+    /// (global $saved_original_environc (mut i32) (i32.const 0))
+    ///
+    /// i32 $wasi_preset_env.environ_sizes_get(size_t *environc_ptr, size_t *environ_buf_size_ptr) {
+    ///   i32 err = $wasi_snapshot_preview1.environ_sizes_get(environc_ptr, environ_buf_size_ptr);
+    ///   if (err != __WASI_ERRNO_SUCCESS) {
+    ///     return err;
+    ///   }
+    ///   $saved_original_environc = *environc_ptr;
+    ///   if ($snapshot_environ == NULL) {
+    ///     take_snapshot(...);
+    ///   }
+    ///   // Drop the entries rejected by is_host_var_allowed, compacting $snapshot_environ
+    ///   retain($snapshot_environ, is_host_var_allowed);
+    ///   size_t host_size = 0;
+    ///   for (i32 index = 0; index < $snapshot_environc; index++) {
+    ///     host_size += strlen($snapshot_environ[index]) + 1;
+    ///   }
+    ///   if (__builtin_add_overflow($snapshot_environc, PRESET_ENV_LEN(), environc_ptr) ||
+    ///       __builtin_add_overflow(host_size, PRESET_ENV_SIZE(), environ_buf_size_ptr)) {
+    ///     return __WASI_ERRNO_OVERFLOW;
+    ///   }
+    ///   return __WASI_ERRNO_SUCCESS;
+    /// }
+    /// i32 $wasi_preset_env.environ_get(char **environ, char *environ_buf) {
+    ///   memcpy(environ_buf, PRESET_ENV_DATA(), PRESET_ENV_SIZE());
+    ///   environ[0] = environ_buf + PRESET_ENV_OFFSET(0);
+    ///   ...
+    ///   environ[PRESET_ENV_LEN() - 1] = environ_buf + PRESET_ENV_OFFSET(PRESET_ENV_LEN() - 1);
+    ///   environ += PRESET_ENV_LEN();
+    ///   environ_buf += PRESET_ENV_SIZE();
+    ///   for (i32 index = 0; index < $snapshot_environc; index++) {
+    ///     *environ++ = environ_buf;
+    ///     environ_buf = stpcpy(environ_buf, $snapshot_environ[index]) + 1;
+    ///   }
+    ///   return __WASI_ERRNO_SUCCESS;
+    /// }
+    /// ```
+    ///
+    /// When nothing is dropped, that is, every variable has [`EnvPrecedence::Host`] or
+    /// [`EnvPrecedence::Error`] and the runtime ones are not filtered, the snapshot is used
+    /// only for the lookups below, the sizes are the sums of the runtime ones and the preset
+    /// ones, and `environ_get` lets the original one write the runtime ones:
+    ///
+    /// ```c
+    ///   // Some runtimes reject environ_get with no environment variables.
+    ///   if ($saved_original_environc == 0) {
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   return $wasi_snapshot_preview1.environ_get(environ, environ_buf);
+    /// ```
+    ///
+    /// ### Precedence
+    ///
+    /// After dropping the rejected entries, `environ_sizes_get` looks up the keys of the
+    /// other variables in the snapshot:
+    ///
+    /// ```c
+    /// $saved_hidden = 0;
+    /// if (getenv_in_snapshot(KEY(i)) != NULL) {
+    ///   // EnvPrecedence::Host
+    ///   $saved_hidden |= 1 << HIDDEN_INDEX(i);
+    ///   *environc_ptr -= 1;
+    ///   *environ_buf_size_ptr -= strlen(PRESET_ENV(i)) + 1;
+    ///   // EnvPrecedence::Error
+    ///   return __WASI_ERRNO_INVAL;
+    /// }
+    /// ```
+    ///
+    /// and `environ_get` skips the hidden ones, packing the rest at the front of environ_buf.
+    ///
+    /// When used with [`crate::PresetArgs::expand_env`], run this after
+    /// [`crate::PresetArgs::run`] so that `${NAME}` in the preset args is expanded with the
+    /// preset environment variables too.
+    ///
+    /// ## Limitations
+    ///
+    /// The memory grown for the snapshot, the size of the runtime environment plus a pointer
+    /// per variable rounded up to pages, is never released. It's grown once per instance,
+    /// and again only after the original `environ_get` fails.
+    pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
+        if self.hidden_vars().count() > 32 {
+            anyhow::bail!(
+                "too many env vars with the host precedence: {} (at most 32)",
+                self.hidden_vars().count()
+            );
+        }
+        let is_host_var_allowed = self.add_is_host_var_allowed(module)?;
+        let snapshot = if is_host_var_allowed.is_some() || !self.vars.is_empty() {
+            Some(EnvSnapshot::new(module))
+        } else {
+            None
+        };
+        let mut add_i32_global = || {
+            module
+                .globals
+                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)))
+        };
+        let saved = SavedEnv {
+            original_environc: add_i32_global(),
+            hidden: if self.hidden_vars().next().is_some() {
+                Some(add_i32_global())
+            } else {
+                None
+            },
+            snapshot,
+        };
+        let mut interposer = Interposer::new("wasi_preset_env");
+        interposer
+            .interpose(
+                &self.wasi_module_name,
+                "environ_sizes_get",
+                |module, original| {
+                    let original_environ_get =
+                        get_import_function(module, &self.wasi_module_name, "environ_get")?;
+                    self.add_environ_sizes_get(
+                        module,
+                        (original, original_environ_get),
+                        &saved,
+                        is_host_var_allowed,
+                    )
+                },
+            )
+            .interpose(&self.wasi_module_name, "environ_get", |module, original| {
+                self.add_environ_get(module, original, &saved, is_host_var_allowed.is_some())
+            });
+        interposer.run(module)
+    }
+
+    fn preset_env_size(&self) -> usize {
+        self.vars.iter().map(|var| var.var.len() + 1).sum()
+    }
+
+    /// The variables hidden when the runtime gives the same key, with the bit index in
+    /// `$saved_hidden`.
+    fn hidden_vars(&self) -> impl Iterator<Item = (usize, &PresetVar)> {
+        self.vars
+            .iter()
+            .filter(|var| var.precedence == EnvPrecedence::Host)
+            .enumerate()
+    }
+
+    fn hidden_index(&self, var: &PresetVar) -> Option<usize> {
+        self.hidden_vars()
+            .find(|(_, hidden)| std::ptr::eq(*hidden, var))
+            .map(|(i, _)| i)
+    }
+
+    /// Add `$wasi_preset_env.is_host_var_allowed (char *var) (result i32)` returning 1 if the
+    /// key of the `KEY=VALUE` string passes the filters and is not the key of a variable with
+    /// [`EnvPrecedence::Preset`]. Returns `None` if there is nothing to drop.
+    fn add_is_host_var_allowed(&self, module: &mut Module) -> anyhow::Result<Option<FunctionId>> {
+        // Exact patterns match `KEY=`, and prefix ones match `PREFIX`.
+        let prefix = |pattern: &ArgPattern| match pattern {
+            ArgPattern::Exact(key) => [key.to_string_lossy().as_bytes(), b"="].concat(),
+            ArgPattern::Prefix(prefix) => prefix.to_string_lossy().as_bytes().to_vec(),
+        };
+        let denied = self
+            .vars
+            .iter()
+            .filter(|var| var.precedence == EnvPrecedence::Preset)
+            .map(|var| [var.key.as_slice(), b"="].concat())
+            .chain(self.denied_host_vars.iter().map(prefix))
+            .collect::<Vec<_>>();
+        let allowed = self
+            .allowed_host_vars
+            .iter()
+            .map(prefix)
+            .collect::<Vec<_>>();
+        if denied.is_empty() && allowed.is_empty() {
+            return Ok(None);
+        }
+        let memory = first_memory(module)?;
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        let var = module.locals.add(ValType::I32);
+        builder.name("wasi_preset_env.is_host_var_allowed".to_string());
+
+        let mut body = builder.func_body();
+        for prefix in &denied {
+            // if (keymatch(var, PATTERN)) return 0;
+            string_matches_at(&mut body, memory, prefix, var, false);
+            body.if_else(
+                None,
+                |then| {
+                    then.i32_const(0).return_();
+                },
+                |_| {},
+            );
+        }
+        for prefix in &allowed {
+            // if (keymatch(var, PATTERN)) return 1;
+            string_matches_at(&mut body, memory, prefix, var, false);
+            body.if_else(
+                None,
+                |then| {
+                    then.i32_const(1).return_();
+                },
+                |_| {},
+            );
+        }
+        // Allowed only if there is no allowlist
+        body.i32_const(allowed.is_empty().into());
+        Ok(Some(builder.finish(vec![var], &mut module.funcs)))
+    }
+
+    fn add_environ_sizes_get(
+        &self,
+        module: &mut Module,
+        (original, original_environ_get): (FunctionId, FunctionId),
+        saved: &SavedEnv,
+        is_host_var_allowed: Option<FunctionId>,
+    ) -> anyhow::Result<FunctionId> {
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_env.environ_sizes_get".to_string());
+
+        // Arguments
+        let environc_ptr = module.locals.add(ValType::I32);
+        let environ_buf_size_ptr = module.locals.add(ValType::I32);
+        // Locals
+        let err = module.locals.add(ValType::I32);
+        let value = module.locals.add(ValType::I32);
+        let count = module.locals.add(ValType::I32);
+        let size = module.locals.add(ValType::I32);
+        let index = module.locals.add(ValType::I32);
+        let p = module.locals.add(ValType::I32);
+        let byte = module.locals.add(ValType::I32);
+        let memory = first_memory(module)?;
+
+        let mut body = builder.func_body();
+        // i32 err = $wasi_snapshot_preview1.environ_sizes_get(environc_ptr, environ_buf_size_ptr);
+        body.local_get(environc_ptr)
+            .local_get(environ_buf_size_ptr)
+            .call(original)
+            .local_tee(err)
+            .if_else(
+                None,
+                |then| {
+                    then.local_get(err).return_();
+                },
+                |_| {},
+            );
+        // $saved_original_environc = *environc_ptr;
+        body.local_get(environc_ptr);
+        load_i32(&mut body, memory).global_set(saved.original_environc);
+        // i32 count = *environc_ptr + PRESET_ENV_LEN();
+        // i32 size = *environ_buf_size_ptr + PRESET_ENV_SIZE();
+        for (ptr, addend, local) in [
+            (environc_ptr, self.vars.len(), count),
+            (environ_buf_size_ptr, self.preset_env_size(), size),
+        ] {
+            body.local_get(ptr);
+            load_i32(&mut body, memory).local_set(local);
+            body.const_(usize_to_wasm_i32(addend));
+            add_checked(&mut body, local, err, crate::__WASI_ERRNO_OVERFLOW as u16);
+        }
+
+        if let Some(snapshot) = saved.snapshot {
+            // The snapshot clobbers the values pointed by the arguments, which are
+            // overwritten below.
+            snapshot.take(
+                &mut body,
+                memory,
+                (original, original_environ_get),
+                (environc_ptr, environ_buf_size_ptr),
+                index,
+                value,
+                err,
+                crate::__WASI_ERRNO_OVERFLOW as u16,
+            );
+            if let Some(is_host_var_allowed) = is_host_var_allowed {
+                snapshot.retain(&mut body, memory, is_host_var_allowed, index, value, p);
+                // count = $snapshot_environc + PRESET_ENV_LEN();
+                body.global_get(snapshot.environc)
+                    .const_(usize_to_wasm_i32(self.vars.len()))
+                    .binop(BinaryOp::I32Add)
+                    .local_set(count);
+                // i32 host_size = 0;
+                // for (index = 0; index < $snapshot_environc; index++) {
+                //   host_size += strlen($snapshot_environ[index]) + 1;
+                // }
+                body.i32_const(0).local_tee(value).local_set(index);
+                body.block(None, |done| {
+                    let done_id = done.id();
+                    done.loop_(None, |loop_| {
+                        let loop_id = loop_.id();
+                        loop_
+                            .local_get(index)
+                            .global_get(snapshot.environc)
+                            .binop(BinaryOp::I32GeU)
+                            .br_if(done_id);
+                        snapshot.push_entry_ptr(loop_, index);
+                        load_i32(loop_, memory).local_set(p);
+                        add_string_size(loop_, memory, p, value, byte);
+                        loop_
+                            .local_get(index)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .local_set(index);
+                        loop_.br(loop_id);
+                    });
+                });
+                // size = host_size + PRESET_ENV_SIZE();
+                body.const_(usize_to_wasm_i32(self.preset_env_size()))
+                    .local_set(size);
+                body.local_get(value);
+                add_checked(&mut body, size, err, crate::__WASI_ERRNO_OVERFLOW as u16);
+            }
+            if let Some(hidden) = saved.hidden {
+                body.i32_const(0).global_set(hidden);
+            }
+            for var in self.vars.iter() {
+                if var.precedence == EnvPrecedence::Preset {
+                    continue;
+                }
+                snapshot.push_getenv(&mut body, memory, &var.key, index, p);
+                let hidden_index = self.hidden_index(var);
+                body.if_else(
+                    None,
+                    |then| match (var.precedence, hidden_index, saved.hidden) {
+                        (EnvPrecedence::Host, Some(i), Some(hidden)) => {
+                            // $saved_hidden |= 1 << HIDDEN_INDEX;
+                            then.global_get(hidden)
+                                .i32_const(1 << i)
+                                .binop(BinaryOp::I32Or)
+                                .global_set(hidden);
+                            // count -= 1; size -= strlen(PRESET_ENV(i)) + 1;
+                            for (local, n) in [(count, 1), (size, var.var.len() + 1)] {
+                                then.local_get(local)
+                                    .const_(usize_to_wasm_i32(n))
+                                    .binop(BinaryOp::I32Sub)
+                                    .local_set(local);
+                            }
+                        }
+                        _ => {
+                            then.i32_const(crate::__WASI_ERRNO_INVAL).return_();
+                        }
+                    },
+                    |_| {},
+                );
+            }
+        }
+
+        // *environc_ptr = count; *environ_buf_size_ptr = size;
+        body.local_get(environc_ptr).local_get(count);
+        store_i32(&mut body, memory);
+        body.local_get(environ_buf_size_ptr).local_get(size);
+        store_i32(&mut body, memory);
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        Ok(builder.finish(vec![environc_ptr, environ_buf_size_ptr], &mut module.funcs))
+    }
+
+    fn add_environ_get(
+        &self,
+        module: &mut Module,
+        original: FunctionId,
+        saved: &SavedEnv,
+        filters_host_vars: bool,
+    ) -> anyhow::Result<FunctionId> {
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_env.environ_get".to_string());
+
+        // Arguments
+        let environ = module.locals.add(ValType::I32);
+        let environ_buf = module.locals.add(ValType::I32);
+        // Locals
+        let index = module.locals.add(ValType::I32);
+        let p = module.locals.add(ValType::I32);
+        let byte = module.locals.add(ValType::I32);
+        let memory = first_memory(module)?;
+
+        let mut body = builder.func_body();
+        for var in self.vars.iter() {
+            let write = |builder: &mut InstrSeqBuilder| {
+                // *environ++ = environ_buf;
+                builder.local_get(environ).local_get(environ_buf);
+                store_i32(builder, memory);
+                builder
+                    .local_get(environ)
+                    .i32_const(4)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(environ);
+                // environ_buf = stpcpy(environ_buf, PRESET_ENV(i)) + 1;
+                store_string_at(builder, memory, &var.var, environ_buf, 0);
+                builder
+                    .local_get(environ_buf)
+                    .const_(usize_to_wasm_i32(var.var.len() + 1))
+                    .binop(BinaryOp::I32Add)
+                    .local_set(environ_buf);
+            };
+            match (self.hidden_index(var), saved.hidden) {
+                (Some(i), Some(hidden)) => {
+                    // if (!($saved_hidden & (1 << HIDDEN_INDEX))) { ... }
+                    body.global_get(hidden)
+                        .i32_const(1 << i)
+                        .binop(BinaryOp::I32And)
+                        .if_else(None, |_| {}, write);
+                }
+                _ => write(&mut body),
+            }
+        }
+        if let (true, Some(snapshot)) = (filters_host_vars, saved.snapshot) {
+            // for (index = 0; index < $snapshot_environc; index++) { ... }
+            body.i32_const(0).local_set(index);
+            body.block(None, |done| {
+                let done_id = done.id();
+                done.loop_(None, |loop_| {
+                    let loop_id = loop_.id();
+                    loop_
+                        .local_get(index)
+                        .global_get(snapshot.environc)
+                        .binop(BinaryOp::I32GeU)
+                        .br_if(done_id);
+                    // *environ++ = environ_buf;
+                    loop_.local_get(environ).local_get(environ_buf);
+                    store_i32(loop_, memory);
+                    loop_
+                        .local_get(environ)
+                        .i32_const(4)
+                        .binop(BinaryOp::I32Add)
+                        .local_set(environ);
+                    // environ_buf = stpcpy(environ_buf, $snapshot_environ[index++]) + 1;
+                    snapshot.push_entry_ptr(loop_, index);
+                    load_i32(loop_, memory).local_set(p);
+                    copy_string(loop_, memory, environ_buf, p, byte);
+                    loop_
+                        .local_get(index)
+                        .i32_const(1)
+                        .binop(BinaryOp::I32Add)
+                        .local_set(index);
+                    loop_.br(loop_id);
+                });
+            });
+            body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+            return Ok(builder.finish(vec![environ, environ_buf], &mut module.funcs));
+        }
+        // if ($saved_original_environc == 0) return __WASI_ERRNO_SUCCESS;
+        body.global_get(saved.original_environc).if_else(
+            None,
+            |_| {},
+            |else_| {
+                else_.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+            },
+        );
+        // return $wasi_snapshot_preview1.environ_get(environ, environ_buf);
+        body.local_get(environ)
+            .local_get(environ_buf)
+            .call(original);
+        Ok(builder.finish(vec![environ, environ_buf], &mut module.funcs))
+    }
+}
+
+/// Global variables shared between the two functions
+struct SavedEnv {
+    original_environc: GlobalId,
+    /// Bits of the [`EnvPrecedence::Host`] variables given by the runtime too
+    hidden: Option<GlobalId>,
+    /// Snapshot of the runtime environment variables taken by `environ_sizes_get`
+    snapshot: Option<EnvSnapshot>,
+}
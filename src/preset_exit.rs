@@ -0,0 +1,135 @@
+//! Remapping the exit code of a WASI program before it reaches the runtime.
+
+use walrus::{ir::BinaryOp, FunctionBuilder, FunctionId, Module, ValType};
+
+use crate::Interposer;
+
+/// Exit codes to remap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCodePattern {
+    /// Matches the exit code
+    Code(u32),
+    /// Matches any nonzero exit codes not matched by [`ExitCodePattern::Code`]
+    NonZero,
+}
+
+impl std::str::FromStr for ExitCodePattern {
+    type Err = anyhow::Error;
+
+    /// Parse a pattern: an exit code, or `nonzero`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nonzero" => Ok(ExitCodePattern::NonZero),
+            _ => match s.parse() {
+                Ok(code) => Ok(ExitCodePattern::Code(code)),
+                Err(_) => anyhow::bail!("invalid exit code: {} (expected a number or nonzero)", s),
+            },
+        }
+    }
+}
+
+pub struct PresetExit {
+    /// (from, to)
+    codes: Vec<(u32, u32)>,
+    nonzero: Option<u32>,
+    wasi_module_name: String,
+}
+
+impl Default for PresetExit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PresetExit {
+    pub fn new() -> Self {
+        Self {
+            codes: vec![],
+            nonzero: None,
+            wasi_module_name: "wasi_snapshot_preview1".to_string(),
+        }
+    }
+
+    /// Make the program exit with `to` instead of the exit codes matching `from`, e.g. 1 to 0
+    /// for the tools exiting with 1 on warnings. The exact codes take precedence over
+    /// [`ExitCodePattern::NonZero`], and the other exit codes are left as is.
+    pub fn map(&mut self, from: ExitCodePattern, to: u32) -> &mut Self {
+        match from {
+            ExitCodePattern::Code(code) => {
+                self.codes.retain(|(from, _)| *from != code);
+                self.codes.push((code, to));
+            }
+            ExitCodePattern::NonZero => self.nonzero = Some(to),
+        }
+        self
+    }
+
+    /// Instrument the input Wasm so that it exits with the remapped exit codes.
+    ///
+    /// ## Code Shape
+    ///
+    /// Like [`crate::PresetArgs::run`], this adds a proxy of `proc_exit`:
+    ///
+    /// ```c
+    /// void $wasi_preset_exit.proc_exit(u32 code) {
+    ///   if (code == FROM(i)) {
+    ///     code = TO(i);
+    ///   } else if ...
+    ///   } else if (code != 0) {
+    ///     code = NONZERO;
+    ///   }
+    ///   $wasi_snapshot_preview1.proc_exit(code);
+    /// }
+    /// ```
+    ///
+    /// ## Limitations
+    ///
+    /// wasi-libc calls `proc_exit` only when `main` returns a nonzero code or `exit` is
+    /// called, so mapping 0 doesn't affect the programs returning 0 from `main`.
+    pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
+        let mut interposer = Interposer::new("wasi_preset_exit");
+        interposer.interpose(&self.wasi_module_name, "proc_exit", |module, original| {
+            Ok(self.add_proc_exit(module, original))
+        });
+        interposer.run(module)
+    }
+
+    fn add_proc_exit(&self, module: &mut Module, original: FunctionId) -> FunctionId {
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_exit.proc_exit".to_string());
+        // Arguments
+        let code = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        body.block(None, |done| {
+            let done_id = done.id();
+            for (from, to) in &self.codes {
+                // if (code == FROM) { code = TO; goto done; }
+                done.local_get(code)
+                    .i32_const(*from as i32)
+                    .binop(BinaryOp::I32Eq)
+                    .if_else(
+                        None,
+                        |then| {
+                            then.i32_const(*to as i32).local_set(code).br(done_id);
+                        },
+                        |_| {},
+                    );
+            }
+            if let Some(to) = self.nonzero {
+                // if (code != 0) code = NONZERO;
+                done.local_get(code).if_else(
+                    None,
+                    |then| {
+                        then.i32_const(to as i32).local_set(code);
+                    },
+                    |_| {},
+                );
+            }
+        });
+        // $wasi_snapshot_preview1.proc_exit(code);
+        body.local_get(code).call(original);
+        builder.finish(vec![code], &mut module.funcs)
+    }
+}
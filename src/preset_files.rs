@@ -0,0 +1,1011 @@
+//! Serving the read-only files embedded in the module under a virtual directory.
+
+use std::ffi::OsString;
+
+use walrus::{
+    ir::{BinaryOp, UnaryOp, Value},
+    DataId, DataKind, FunctionBuilder, FunctionId, GlobalId, InitExpr, InstrSeqBuilder, LocalId,
+    MemoryId, Module, ValType,
+};
+
+use crate::{
+    first_memory, load_i32, preset_stdin::read_data, store_bytes_at, store_i32, store_i64,
+    string_matches_at, usize_to_wasm_i32, Interposer, __WASI_ERRNO_BADF, __WASI_ERRNO_NAMETOOLONG,
+    __WASI_ERRNO_NFILE, __WASI_ERRNO_NOENT, __WASI_ERRNO_NOTDIR, __WASI_ERRNO_ROFS,
+    __WASI_PREOPENTYPE_DIR, PRESTAT_NAME_LEN_OFFSET,
+};
+
+pub struct PresetFiles {
+    /// Name of the virtual directory reported as a preopened directory
+    dir: Vec<u8>,
+    /// (path relative to the directory, content)
+    files: Vec<(Vec<u8>, Vec<u8>)>,
+    wasi_module_name: String,
+}
+
+impl PresetFiles {
+    pub fn new(dir: OsString) -> Self {
+        Self {
+            dir: dir.to_string_lossy().as_bytes().to_vec(),
+            files: vec![],
+            wasi_module_name: "wasi_snapshot_preview1".to_string(),
+        }
+    }
+
+    /// Embed a file at `path` relative to the virtual directory, e.g. "app.toml" or
+    /// "conf/app.toml".
+    pub fn file(&mut self, path: OsString, content: Vec<u8>) -> &mut Self {
+        self.files
+            .push((path.to_string_lossy().as_bytes().to_vec(), content));
+        self
+    }
+
+    /// Instrument the input Wasm so that it sees the embedded files under the virtual
+    /// directory.
+    ///
+    /// ## Code Shape
+    ///
+    /// Like [`crate::PresetArgs::run`], this adds proxies of the following functions if
+    /// imported. `path_open` is required.
+    ///
+    /// - `fd_prestat_get` and `fd_prestat_dir_name` report the virtual directory at the first
+    ///   fd for which the runtime returns `__WASI_ERRNO_BADF`, so that wasi-libc finds it
+    ///   next to the directories preopened by the runtime.
+    /// - `path_open` on the virtual directory opens an embedded file as a synthetic fd.
+    /// - `fd_read`, `fd_seek`, `fd_fdstat_get`, `fd_filestat_get` and `fd_close` serve the
+    ///   synthetic fds.
+    /// - The fd of the virtual directory is free for the runtime, so it may give the number to
+    ///   a file opened by the program. `path_open` and `sock_accept` show the program such an
+    ///   fd as `RENAMED_FD`, and all the functions taking fds translate it back.
+    ///
+    /// Each content is stored in a passive data segment, and the open files are kept in a
+    /// table grown in the memory on the first `path_open`:
+    ///
+    /// ```c
+    /// i32 $dir_fd = -1;
+    /// struct { u32 file; u32 offset; } *$open_files = NULL;
+    ///
+    /// i32 $wasi_preset_files.fd_prestat_get(i32 fd, __wasi_prestat_t *buf) {
+    ///   i32 err = $wasi_snapshot_preview1.fd_prestat_get(fd, buf);
+    ///   if (err == __WASI_ERRNO_BADF && fd >= 3 && ($dir_fd == -1 || $dir_fd == fd)) {
+    ///     $dir_fd = fd;
+    ///     *buf = (__wasi_prestat_t){ __WASI_PREOPENTYPE_DIR, strlen(DIR) };
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   return err;
+    /// }
+    /// i32 $wasi_preset_files.path_open(i32 fd, ..., const char *path, size_t path_len,
+    ///                                  ..., __wasi_fd_t *opened_fd) {
+    ///   if (fd != $dir_fd) {
+    ///     if (fd == RENAMED_FD) fd = $dir_fd;
+    ///     i32 err = $wasi_snapshot_preview1.path_open(fd, ...);
+    ///     if (err == __WASI_ERRNO_SUCCESS && *opened_fd == $dir_fd) *opened_fd = RENAMED_FD;
+    ///     return err;
+    ///   }
+    ///   if (path_len == strlen(PATH(i)) && memcmp(path, PATH(i), path_len) == 0) {
+    ///     find a free slot k of $open_files;
+    ///     $open_files[k] = { i + 1, 0 };
+    ///     *opened_fd = FILE_FD_BASE + k;
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   ...
+    ///   return __WASI_ERRNO_NOENT;
+    /// }
+    /// i32 $wasi_preset_files.fd_read(i32 fd, const __wasi_iovec_t *iovs, size_t iovs_len,
+    ///                                size_t *nread) {
+    ///   if (fd - FILE_FD_BASE >= MAX_OPEN_FILES) {
+    ///     if (fd == $dir_fd) return __WASI_ERRNO_BADF;
+    ///     if (fd == RENAMED_FD) fd = $dir_fd;
+    ///     return $wasi_snapshot_preview1.fd_read(fd, iovs, iovs_len, nread);
+    ///   }
+    ///   if (!(open_file = $wasi_preset_files.get_open_file(fd))) return __WASI_ERRNO_BADF;
+    ///   copy the content of the file from open_file->offset into the iovecs;
+    ///   return __WASI_ERRNO_SUCCESS;
+    /// }
+    /// ```
+    ///
+    /// ## Limitations
+    ///
+    /// - The virtual directory can't be listed, and the paths are compared as is without
+    ///   resolving "." or "..".
+    /// - At most 64 embedded files can be open at a time, and each of them must be smaller
+    ///   than 2 GiB.
+    /// - The fds in the subscriptions given to `poll_oneoff` are not translated, so polling
+    ///   the renamed fd polls nothing. The memory grown for the table of the open files is
+    ///   never released.
+    pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
+        if let Some((path, _)) = self
+            .files
+            .iter()
+            .find(|(_, content)| content.len() > i32::MAX as usize)
+        {
+            anyhow::bail!("too large embedded file: {}", String::from_utf8_lossy(path));
+        }
+        let cx = &self.add_context(module)?;
+        let mut interposer = Interposer::new("wasi_preset_files");
+        // `path_open` is required, and the other ones are optional.
+        interposer.interpose(&self.wasi_module_name, "path_open", |module, original| {
+            Ok(self.add_path_open(module, cx, original))
+        });
+        let names = [
+            "fd_prestat_get",
+            "fd_prestat_dir_name",
+            "fd_read",
+            "fd_seek",
+            "fd_fdstat_get",
+            "fd_filestat_get",
+            "fd_close",
+        ];
+        for name in names {
+            interposer.interpose_if_imported(
+                &self.wasi_module_name,
+                name,
+                move |module, original| {
+                    Ok(match name {
+                        "fd_prestat_get" => self.add_fd_prestat_get(module, cx, original),
+                        "fd_prestat_dir_name" => self.add_fd_prestat_dir_name(module, cx, original),
+                        "fd_read" => self.add_fd_read(module, cx, original),
+                        "fd_seek" => self.add_fd_seek(module, cx, original),
+                        "fd_fdstat_get" => self.add_fd_fdstat_get(module, cx, original),
+                        "fd_filestat_get" => self.add_fd_filestat_get(module, cx, original),
+                        "fd_close" => self.add_fd_close(module, cx, original),
+                        _ => unreachable!(),
+                    })
+                },
+            );
+        }
+        for (name, fds, opened_fd) in FD_FUNCTIONS {
+            interposer.interpose_if_imported(
+                &self.wasi_module_name,
+                name,
+                move |module, original| add_fd_proxy(module, cx, original, name, (fds, *opened_fd)),
+            );
+        }
+        interposer.run(module)
+    }
+
+    fn add_context(&self, module: &mut Module) -> anyhow::Result<Context> {
+        let memory = first_memory(module)?;
+        let dir_fd = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(-1)));
+        let open_files =
+            module
+                .globals
+                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+        let data = self
+            .files
+            .iter()
+            .map(|(_, content)| module.data.add(DataKind::Passive, content.clone()))
+            .collect();
+
+        // i32 $wasi_preset_files.get_open_file(i32 fd)
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder.name("wasi_preset_files.get_open_file".to_string());
+        let fd = module.locals.add(ValType::I32);
+        let open_file = module.locals.add(ValType::I32);
+        let mut body = builder.func_body();
+        // if ($open_files == NULL || fd - FILE_FD_BASE >= MAX_OPEN_FILES) return NULL;
+        body.global_get(open_files)
+            .unop(UnaryOp::I32Eqz)
+            .local_get(fd)
+            .i32_const(FILE_FD_BASE)
+            .binop(BinaryOp::I32Sub)
+            .i32_const(MAX_OPEN_FILES)
+            .binop(BinaryOp::I32GeU)
+            .binop(BinaryOp::I32Or)
+            .if_else(
+                None,
+                |then| {
+                    then.i32_const(0).return_();
+                },
+                |_| {},
+            );
+        // open_file = &$open_files[fd - FILE_FD_BASE];
+        body.global_get(open_files)
+            .local_get(fd)
+            .i32_const(FILE_FD_BASE)
+            .binop(BinaryOp::I32Sub)
+            .i32_const(OPEN_FILE_SIZE)
+            .binop(BinaryOp::I32Mul)
+            .binop(BinaryOp::I32Add)
+            .local_set(open_file);
+        // return open_file->file ? open_file : NULL;
+        body.local_get(open_file).i32_const(0).local_get(open_file);
+        load_i32(&mut body, memory).select(None);
+        let get_open_file = builder.finish(vec![fd], &mut module.funcs);
+
+        Ok(Context {
+            memory,
+            dir_fd,
+            open_files,
+            data,
+            get_open_file,
+        })
+    }
+
+    fn add_fd_prestat_get(
+        &self,
+        module: &mut Module,
+        cx: &Context,
+        original: FunctionId,
+    ) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "fd_prestat_get");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let buf = module.locals.add(ValType::I32);
+        // Locals
+        let err = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // if (err == BADF && fd >= 3 && ($dir_fd == -1 || $dir_fd == fd)) { ... }
+        body.local_get(fd)
+            .local_get(buf)
+            .call(original)
+            .local_tee(err)
+            .i32_const(__WASI_ERRNO_BADF)
+            .binop(BinaryOp::I32Eq)
+            .local_get(fd)
+            .i32_const(3)
+            .binop(BinaryOp::I32GeU)
+            .binop(BinaryOp::I32And)
+            .global_get(cx.dir_fd)
+            .i32_const(-1)
+            .binop(BinaryOp::I32Eq)
+            .global_get(cx.dir_fd)
+            .local_get(fd)
+            .binop(BinaryOp::I32Eq)
+            .binop(BinaryOp::I32Or)
+            .binop(BinaryOp::I32And)
+            .if_else(
+                None,
+                |then| {
+                    then.local_get(fd).global_set(cx.dir_fd);
+                    // buf->tag = __WASI_PREOPENTYPE_DIR; buf->u.dir.pr_name_len = strlen(DIR);
+                    then.local_get(buf).i32_const(__WASI_PREOPENTYPE_DIR);
+                    store_i32(then, cx.memory);
+                    then.local_get(buf)
+                        .i32_const(PRESTAT_NAME_LEN_OFFSET)
+                        .binop(BinaryOp::I32Add)
+                        .const_(usize_to_wasm_i32(self.dir.len()));
+                    store_i32(then, cx.memory);
+                    then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                },
+                |_| {},
+            );
+        body.local_get(err);
+        builder.finish(vec![fd, buf], &mut module.funcs)
+    }
+
+    fn add_fd_prestat_dir_name(
+        &self,
+        module: &mut Module,
+        cx: &Context,
+        original: FunctionId,
+    ) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "fd_prestat_dir_name");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let path = module.locals.add(ValType::I32);
+        let path_len = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // if (fd == $dir_fd) { ... }
+        body.local_get(fd)
+            .global_get(cx.dir_fd)
+            .binop(BinaryOp::I32Eq)
+            .if_else(
+                None,
+                |then| {
+                    // if (path_len < strlen(DIR)) return __WASI_ERRNO_NAMETOOLONG;
+                    then.local_get(path_len)
+                        .const_(usize_to_wasm_i32(self.dir.len()))
+                        .binop(BinaryOp::I32LtU)
+                        .if_else(
+                            None,
+                            |then| {
+                                then.i32_const(__WASI_ERRNO_NAMETOOLONG).return_();
+                            },
+                            |_| {},
+                        );
+                    // memcpy(path, DIR, strlen(DIR));
+                    store_bytes_at(then, cx.memory, &self.dir, path, 0);
+                    then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                },
+                |_| {},
+            );
+        body.local_get(fd)
+            .local_get(path)
+            .local_get(path_len)
+            .call(original);
+        builder.finish(vec![fd, path, path_len], &mut module.funcs)
+    }
+
+    fn add_path_open(&self, module: &mut Module, cx: &Context, original: FunctionId) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "path_open");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let dirflags = module.locals.add(ValType::I32);
+        let path = module.locals.add(ValType::I32);
+        let path_len = module.locals.add(ValType::I32);
+        let oflags = module.locals.add(ValType::I32);
+        let fs_rights_base = module.locals.add(ValType::I64);
+        let fs_rights_inheriting = module.locals.add(ValType::I64);
+        let fdflags = module.locals.add(ValType::I32);
+        let opened_fd = module.locals.add(ValType::I32);
+        let args = vec![
+            fd,
+            dirflags,
+            path,
+            path_len,
+            oflags,
+            fs_rights_base,
+            fs_rights_inheriting,
+            fdflags,
+            opened_fd,
+        ];
+        // Locals
+        let open_file = module.locals.add(ValType::I32);
+        let index = module.locals.add(ValType::I32);
+        let err = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // if (fd != $dir_fd) { ... }
+        body.local_get(fd)
+            .global_get(cx.dir_fd)
+            .binop(BinaryOp::I32Ne)
+            .if_else(
+                None,
+                |then| {
+                    // err = $wasi_snapshot_preview1.path_open(translate(fd), ...);
+                    translate_fd(then, cx, fd);
+                    for arg in &args {
+                        then.local_get(*arg);
+                    }
+                    then.call(original).local_set(err);
+                    rename_opened_fd(then, cx, err, opened_fd);
+                    then.local_get(err).return_();
+                },
+                |_| {},
+            );
+        // if (oflags & (CREAT | TRUNC) || fs_rights_base & FD_WRITE) return ROFS;
+        body.local_get(oflags)
+            .i32_const(__WASI_OFLAGS_CREAT | __WASI_OFLAGS_TRUNC)
+            .binop(BinaryOp::I32And)
+            .local_get(fs_rights_base)
+            .i64_const(__WASI_RIGHTS_FD_WRITE)
+            .binop(BinaryOp::I64And)
+            .unop(UnaryOp::I64Eqz)
+            .unop(UnaryOp::I32Eqz)
+            .binop(BinaryOp::I32Or)
+            .if_else(
+                None,
+                |then| {
+                    then.i32_const(__WASI_ERRNO_ROFS).return_();
+                },
+                |_| {},
+            );
+        for (i, (file_path, _)) in self.files.iter().enumerate() {
+            // if (path_len == strlen(PATH) && memcmp(path, PATH, path_len) == 0) { ... }
+            body.local_get(path_len)
+                .const_(usize_to_wasm_i32(file_path.len()))
+                .binop(BinaryOp::I32Eq);
+            string_matches_at(&mut body, cx.memory, file_path, path, false);
+            body.binop(BinaryOp::I32And).if_else(
+                None,
+                |then| {
+                    // if (oflags & DIRECTORY) return __WASI_ERRNO_NOTDIR;
+                    then.local_get(oflags)
+                        .i32_const(__WASI_OFLAGS_DIRECTORY)
+                        .binop(BinaryOp::I32And)
+                        .if_else(
+                            None,
+                            |then| {
+                                then.i32_const(__WASI_ERRNO_NOTDIR).return_();
+                            },
+                            |_| {},
+                        );
+                    allocate_open_file(then, cx, open_file, index);
+                    // open_file->file = i + 1; open_file->offset = 0;
+                    then.local_get(open_file).const_(usize_to_wasm_i32(i + 1));
+                    store_i32(then, cx.memory);
+                    then.local_get(open_file)
+                        .i32_const(OPEN_FILE_OFFSET_OFFSET)
+                        .binop(BinaryOp::I32Add)
+                        .i32_const(0);
+                    store_i32(then, cx.memory);
+                    // *opened_fd = FILE_FD_BASE + index;
+                    then.local_get(opened_fd)
+                        .i32_const(FILE_FD_BASE)
+                        .local_get(index)
+                        .binop(BinaryOp::I32Add);
+                    store_i32(then, cx.memory);
+                    then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                },
+                |_| {},
+            );
+        }
+        body.i32_const(__WASI_ERRNO_NOENT);
+        builder.finish(args, &mut module.funcs)
+    }
+
+    fn add_fd_read(&self, module: &mut Module, cx: &Context, original: FunctionId) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "fd_read");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let iovs = module.locals.add(ValType::I32);
+        let iovs_len = module.locals.add(ValType::I32);
+        let nread = module.locals.add(ValType::I32);
+        let args = vec![fd, iovs, iovs_len, nread];
+        // Locals
+        let open_file = module.locals.add(ValType::I32);
+        let offset = module.locals.add(ValType::I32);
+        let n = module.locals.add(ValType::I32);
+        let iov = module.locals.add(ValType::I32);
+        let len = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        forward_unless_file_fd(&mut body, cx, original, &args, open_file);
+        // offset = open_file->offset;
+        body.local_get(open_file)
+            .i32_const(OPEN_FILE_OFFSET_OFFSET)
+            .binop(BinaryOp::I32Add);
+        load_i32(&mut body, cx.memory).local_set(offset);
+        for (i, (_, content)) in self.files.iter().enumerate() {
+            push_is_file(&mut body, cx, open_file, i);
+            body.if_else(
+                None,
+                |then| {
+                    read_data(
+                        then,
+                        cx.memory,
+                        (cx.data[i], content.len()),
+                        (iovs, iovs_len),
+                        offset,
+                        n,
+                        (iov, len),
+                    );
+                },
+                |_| {},
+            );
+        }
+        // open_file->offset = offset;
+        body.local_get(open_file)
+            .i32_const(OPEN_FILE_OFFSET_OFFSET)
+            .binop(BinaryOp::I32Add)
+            .local_get(offset);
+        store_i32(&mut body, cx.memory);
+        // *nread = n;
+        body.local_get(nread).local_get(n);
+        store_i32(&mut body, cx.memory);
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        builder.finish(args, &mut module.funcs)
+    }
+
+    fn add_fd_seek(&self, module: &mut Module, cx: &Context, original: FunctionId) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "fd_seek");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let offset = module.locals.add(ValType::I64);
+        let whence = module.locals.add(ValType::I32);
+        let newoffset = module.locals.add(ValType::I32);
+        let args = vec![fd, offset, whence, newoffset];
+        // Locals
+        let open_file = module.locals.add(ValType::I32);
+        let base = module.locals.add(ValType::I64);
+
+        let mut body = builder.func_body();
+        forward_unless_file_fd(&mut body, cx, original, &args, open_file);
+        // base = whence == SET ? 0 : whence == CUR ? open_file->offset : FILE_SIZE;
+        body.i64_const(0).local_set(base);
+        body.local_get(whence)
+            .i32_const(__WASI_WHENCE_CUR)
+            .binop(BinaryOp::I32Eq)
+            .if_else(
+                None,
+                |then| {
+                    then.local_get(open_file)
+                        .i32_const(OPEN_FILE_OFFSET_OFFSET)
+                        .binop(BinaryOp::I32Add);
+                    load_i32(then, cx.memory)
+                        .unop(UnaryOp::I64ExtendUI32)
+                        .local_set(base);
+                },
+                |_| {},
+            );
+        body.local_get(whence)
+            .i32_const(__WASI_WHENCE_END)
+            .binop(BinaryOp::I32Eq)
+            .if_else(
+                None,
+                |then| {
+                    self.push_file_size(then, cx, open_file);
+                    then.local_set(base);
+                },
+                |_| {},
+            );
+        // if (whence > END) return __WASI_ERRNO_INVAL;
+        body.local_get(whence)
+            .i32_const(__WASI_WHENCE_END)
+            .binop(BinaryOp::I32GtU)
+            .if_else(
+                None,
+                |then| {
+                    then.i32_const(crate::__WASI_ERRNO_INVAL).return_();
+                },
+                |_| {},
+            );
+        // offset += base; if (offset < 0 || offset > INT32_MAX) return __WASI_ERRNO_INVAL;
+        body.local_get(offset)
+            .local_get(base)
+            .binop(BinaryOp::I64Add)
+            .local_tee(offset)
+            .i64_const(i32::MAX.into())
+            .binop(BinaryOp::I64GtU)
+            .if_else(
+                None,
+                |then| {
+                    then.i32_const(crate::__WASI_ERRNO_INVAL).return_();
+                },
+                |_| {},
+            );
+        // open_file->offset = offset; *newoffset = offset;
+        body.local_get(open_file)
+            .i32_const(OPEN_FILE_OFFSET_OFFSET)
+            .binop(BinaryOp::I32Add)
+            .local_get(offset)
+            .unop(UnaryOp::I32WrapI64);
+        store_i32(&mut body, cx.memory);
+        body.local_get(newoffset).local_get(offset);
+        store_i64(&mut body, cx.memory, 0);
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        builder.finish(args, &mut module.funcs)
+    }
+
+    fn add_fd_fdstat_get(
+        &self,
+        module: &mut Module,
+        cx: &Context,
+        original: FunctionId,
+    ) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "fd_fdstat_get");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let buf = module.locals.add(ValType::I32);
+        let args = vec![fd, buf];
+        // Locals
+        let open_file = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // if (fd == $dir_fd) { *buf = (__wasi_fdstat_t){ DIRECTORY, 0, DIR_RIGHTS, FILE_RIGHTS }; }
+        body.local_get(fd)
+            .global_get(cx.dir_fd)
+            .binop(BinaryOp::I32Eq)
+            .if_else(
+                None,
+                |then| {
+                    store_fdstat(
+                        then,
+                        cx.memory,
+                        buf,
+                        __WASI_FILETYPE_DIRECTORY,
+                        (DIR_RIGHTS, FILE_RIGHTS),
+                    );
+                    then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                },
+                |_| {},
+            );
+        forward_unless_file_fd(&mut body, cx, original, &args, open_file);
+        // *buf = (__wasi_fdstat_t){ REGULAR_FILE, 0, FILE_RIGHTS, 0 };
+        store_fdstat(
+            &mut body,
+            cx.memory,
+            buf,
+            __WASI_FILETYPE_REGULAR_FILE,
+            (FILE_RIGHTS, 0),
+        );
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        builder.finish(args, &mut module.funcs)
+    }
+
+    fn add_fd_filestat_get(
+        &self,
+        module: &mut Module,
+        cx: &Context,
+        original: FunctionId,
+    ) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "fd_filestat_get");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let buf = module.locals.add(ValType::I32);
+        let args = vec![fd, buf];
+        // Locals
+        let open_file = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        forward_unless_file_fd(&mut body, cx, original, &args, open_file);
+        // memset(buf, 0, sizeof(__wasi_filestat_t));
+        for offset in (0..FILESTAT_SIZE).step_by(8) {
+            body.local_get(buf).i64_const(0);
+            store_i64(&mut body, cx.memory, offset);
+        }
+        // buf->ino = open_file->file;
+        body.local_get(buf).local_get(open_file);
+        load_i32(&mut body, cx.memory).unop(UnaryOp::I64ExtendUI32);
+        store_i64(&mut body, cx.memory, FILESTAT_INO_OFFSET);
+        // buf->filetype = REGULAR_FILE;
+        body.local_get(buf)
+            .i32_const(FILESTAT_FILETYPE_OFFSET)
+            .binop(BinaryOp::I32Add)
+            .i32_const(__WASI_FILETYPE_REGULAR_FILE);
+        store_i32(&mut body, cx.memory);
+        // buf->nlink = 1;
+        body.local_get(buf).i64_const(1);
+        store_i64(&mut body, cx.memory, FILESTAT_NLINK_OFFSET);
+        // buf->size = FILE_SIZE;
+        body.local_get(buf);
+        self.push_file_size(&mut body, cx, open_file);
+        store_i64(&mut body, cx.memory, FILESTAT_SIZE_OFFSET);
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        builder.finish(args, &mut module.funcs)
+    }
+
+    fn add_fd_close(&self, module: &mut Module, cx: &Context, original: FunctionId) -> FunctionId {
+        let mut builder = proxy_builder(module, original, "fd_close");
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let args = vec![fd];
+        // Locals
+        let open_file = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        forward_unless_file_fd(&mut body, cx, original, &args, open_file);
+        // open_file->file = 0;
+        body.local_get(open_file).i32_const(0);
+        store_i32(&mut body, cx.memory);
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        builder.finish(args, &mut module.funcs)
+    }
+
+    /// Push the size of the file open as `open_file` as i64.
+    fn push_file_size(&self, builder: &mut InstrSeqBuilder, cx: &Context, open_file: LocalId) {
+        // open_file->file == 1 ? SIZE(0) : open_file->file == 2 ? SIZE(1) : ... : 0
+        builder.i64_const(0);
+        for (i, (_, content)) in self.files.iter().enumerate() {
+            builder.i64_const(content.len() as i64).i64_const(0);
+            push_is_file(builder, cx, open_file, i);
+            builder.select(None).binop(BinaryOp::I64Or);
+        }
+    }
+}
+
+/// The other functions taking fds, with the positions of the fds and of the pointer to the
+/// fd given by the runtime
+const FD_FUNCTIONS: &[(&str, &[usize], Option<usize>)] = &[
+    ("fd_advise", &[0], None),
+    ("fd_allocate", &[0], None),
+    ("fd_datasync", &[0], None),
+    ("fd_fdstat_set_flags", &[0], None),
+    ("fd_fdstat_set_rights", &[0], None),
+    ("fd_filestat_set_size", &[0], None),
+    ("fd_filestat_set_times", &[0], None),
+    ("fd_pread", &[0], None),
+    ("fd_pwrite", &[0], None),
+    ("fd_readdir", &[0], None),
+    ("fd_renumber", &[0, 1], None),
+    ("fd_sync", &[0], None),
+    ("fd_tell", &[0], None),
+    ("fd_write", &[0], None),
+    ("path_create_directory", &[0], None),
+    ("path_filestat_get", &[0], None),
+    ("path_filestat_set_times", &[0], None),
+    ("path_link", &[0, 4], None),
+    ("path_readlink", &[0], None),
+    ("path_remove_directory", &[0], None),
+    ("path_rename", &[0, 3], None),
+    ("path_symlink", &[2], None),
+    ("path_unlink_file", &[0], None),
+    ("sock_accept", &[0], Some(2)),
+    ("sock_recv", &[0], None),
+    ("sock_send", &[0], None),
+    ("sock_shutdown", &[0], None),
+];
+
+/// Code generation context shared by the proxies
+struct Context {
+    memory: MemoryId,
+    /// The fd of the virtual directory, or -1 if not reported yet
+    dir_fd: GlobalId,
+    /// Pointer to the table of the open files, or NULL if not allocated yet
+    open_files: GlobalId,
+    /// Passive data segments of the contents
+    data: Vec<DataId>,
+    /// `$wasi_preset_files.get_open_file (i32 fd) (result i32)` returning the pointer to the
+    /// open file of the synthetic fd, or NULL
+    get_open_file: FunctionId,
+}
+
+fn proxy_builder(module: &mut Module, original: FunctionId, name: &str) -> FunctionBuilder {
+    let sig = module.types.get(module.funcs.get(original).ty()).clone();
+    let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+    builder.name(format!("wasi_preset_files.{}", name));
+    builder
+}
+
+/// Build the proxy of a function taking the fds at `fds`, which passes them to the original
+/// function with [`translate_fd`], and renames the fd stored at `opened_fd` with
+/// [`rename_opened_fd`].
+fn add_fd_proxy(
+    module: &mut Module,
+    cx: &Context,
+    original: FunctionId,
+    name: &str,
+    (fds, opened_fd): (&[usize], Option<usize>),
+) -> anyhow::Result<FunctionId> {
+    let sig = module.types.get(module.funcs.get(original).ty()).clone();
+    let args = sig
+        .params()
+        .iter()
+        .map(|ty| module.locals.add(*ty))
+        .collect::<Vec<_>>();
+    if fds.iter().chain(&opened_fd).any(|i| *i >= args.len()) || sig.results() != [ValType::I32] {
+        anyhow::bail!("unexpected signature of {}", name);
+    }
+    let mut builder = proxy_builder(module, original, name);
+    // Locals
+    let err = module.locals.add(ValType::I32);
+
+    let mut body = builder.func_body();
+    for i in fds {
+        translate_fd(&mut body, cx, args[*i]);
+    }
+    for arg in &args {
+        body.local_get(*arg);
+    }
+    body.call(original);
+    if let Some(i) = opened_fd {
+        body.local_set(err);
+        rename_opened_fd(&mut body, cx, err, args[i]);
+        body.local_get(err);
+    }
+    Ok(builder.finish(args, &mut module.funcs))
+}
+
+/// Translate `fd` given by the program into the fd of the runtime. The virtual directory has
+/// no fd in the runtime, and the fd of the runtime with the same number is renamed to
+/// `RENAMED_FD` by [`rename_opened_fd`].
+///
+/// ```c
+/// if (fd == $dir_fd) return __WASI_ERRNO_BADF;
+/// if (fd == RENAMED_FD) fd = $dir_fd;
+/// ```
+fn translate_fd(builder: &mut InstrSeqBuilder, cx: &Context, fd: LocalId) {
+    builder
+        .local_get(fd)
+        .global_get(cx.dir_fd)
+        .binop(BinaryOp::I32Eq)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(__WASI_ERRNO_BADF).return_();
+            },
+            |_| {},
+        );
+    builder
+        .global_get(cx.dir_fd)
+        .local_get(fd)
+        .local_get(fd)
+        .i32_const(RENAMED_FD)
+        .binop(BinaryOp::I32Eq)
+        .select(None)
+        .local_set(fd);
+}
+
+/// Rename the fd given by the runtime if it has the number of the virtual directory.
+///
+/// ```c
+/// if (err == __WASI_ERRNO_SUCCESS && *opened_fd == $dir_fd) *opened_fd = RENAMED_FD;
+/// ```
+fn rename_opened_fd(builder: &mut InstrSeqBuilder, cx: &Context, err: LocalId, opened_fd: LocalId) {
+    builder.local_get(err).if_else(
+        None,
+        |_| {},
+        |else_| {
+            else_.local_get(opened_fd);
+            load_i32(else_, cx.memory)
+                .global_get(cx.dir_fd)
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        then.local_get(opened_fd).i32_const(RENAMED_FD);
+                        store_i32(then, cx.memory);
+                    },
+                    |_| {},
+                );
+        },
+    );
+}
+
+/// Return the result of the original function unless `fd` is a synthetic fd, and set
+/// `open_file` to its open file otherwise.
+///
+/// ```c
+/// if (fd - FILE_FD_BASE >= MAX_OPEN_FILES) return ORIGINAL(translate(fd), args...);
+/// if (!(open_file = $wasi_preset_files.get_open_file(fd))) return __WASI_ERRNO_BADF;
+/// ```
+fn forward_unless_file_fd(
+    builder: &mut InstrSeqBuilder,
+    cx: &Context,
+    original: FunctionId,
+    args: &[LocalId],
+    open_file: LocalId,
+) {
+    builder
+        .local_get(args[0])
+        .i32_const(FILE_FD_BASE)
+        .binop(BinaryOp::I32Sub)
+        .i32_const(MAX_OPEN_FILES)
+        .binop(BinaryOp::I32GeU)
+        .if_else(
+            None,
+            |then| {
+                translate_fd(then, cx, args[0]);
+                for arg in args {
+                    then.local_get(*arg);
+                }
+                then.call(original).return_();
+            },
+            |_| {},
+        );
+    builder
+        .local_get(args[0])
+        .call(cx.get_open_file)
+        .local_tee(open_file)
+        .unop(UnaryOp::I32Eqz)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(__WASI_ERRNO_BADF).return_();
+            },
+            |_| {},
+        );
+}
+
+/// Set `open_file` and `index` to a free slot of the open files, allocating the table on the
+/// first call. Return `__WASI_ERRNO_NFILE` if all of them are used, or
+/// `__WASI_ERRNO_NOMEM` if the memory can't grow.
+fn allocate_open_file(
+    builder: &mut InstrSeqBuilder,
+    cx: &Context,
+    open_file: LocalId,
+    index: LocalId,
+) {
+    // if ($open_files == NULL) $open_files = memory.grow(1) * 0x10000;
+    builder.global_get(cx.open_files).if_else(
+        None,
+        |_| {},
+        |else_| {
+            else_
+                .i32_const(1)
+                .memory_grow(cx.memory)
+                .local_tee(index)
+                .i32_const(-1)
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        then.i32_const(crate::__WASI_ERRNO_NOMEM).return_();
+                    },
+                    |_| {},
+                );
+            else_
+                .local_get(index)
+                .i32_const(16)
+                .binop(BinaryOp::I32Shl)
+                .global_set(cx.open_files);
+        },
+    );
+    // for (index = 0; $open_files[index].file != 0; index++) {
+    //   if (index == MAX_OPEN_FILES) return __WASI_ERRNO_NFILE;
+    // }
+    builder.i32_const(0).local_set(index);
+    builder.block(None, |found| {
+        let found_id = found.id();
+        found.loop_(None, |loop_| {
+            let loop_id = loop_.id();
+            loop_
+                .local_get(index)
+                .i32_const(MAX_OPEN_FILES)
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        then.i32_const(__WASI_ERRNO_NFILE).return_();
+                    },
+                    |_| {},
+                );
+            loop_
+                .global_get(cx.open_files)
+                .local_get(index)
+                .i32_const(OPEN_FILE_SIZE)
+                .binop(BinaryOp::I32Mul)
+                .binop(BinaryOp::I32Add)
+                .local_tee(open_file);
+            load_i32(loop_, cx.memory)
+                .unop(UnaryOp::I32Eqz)
+                .br_if(found_id);
+            loop_
+                .local_get(index)
+                .i32_const(1)
+                .binop(BinaryOp::I32Add)
+                .local_set(index);
+            loop_.br(loop_id);
+        });
+    });
+}
+
+/// Push whether `open_file` is the file at `index`.
+fn push_is_file(builder: &mut InstrSeqBuilder, cx: &Context, open_file: LocalId, index: usize) {
+    builder.local_get(open_file);
+    load_i32(builder, cx.memory)
+        .const_(usize_to_wasm_i32(index + 1))
+        .binop(BinaryOp::I32Eq);
+}
+
+fn store_fdstat(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    buf: LocalId,
+    filetype: i32,
+    (rights_base, rights_inheriting): (i64, i64),
+) {
+    // buf->fs_filetype = FILETYPE; buf->fs_flags = 0;
+    builder.local_get(buf).i32_const(filetype);
+    store_i32(builder, memory);
+    builder.local_get(buf).i64_const(rights_base);
+    store_i64(builder, memory, FDSTAT_RIGHTS_BASE_OFFSET);
+    builder.local_get(buf).i64_const(rights_inheriting);
+    store_i64(builder, memory, FDSTAT_RIGHTS_INHERITING_OFFSET);
+}
+
+/// The first synthetic fd of the embedded files, far from the ones given by the runtimes
+const FILE_FD_BASE: i32 = 0x4000_0000;
+const MAX_OPEN_FILES: i32 = 64;
+/// The fd showing the program the fd of the runtime numbered as the virtual directory
+const RENAMED_FD: i32 = FILE_FD_BASE + MAX_OPEN_FILES;
+/// sizeof(*$open_files)
+const OPEN_FILE_SIZE: i32 = 8;
+/// offsetof(*$open_files, offset)
+const OPEN_FILE_OFFSET_OFFSET: i32 = 4;
+
+/// offsetof(__wasi_fdstat_t, fs_rights_base)
+const FDSTAT_RIGHTS_BASE_OFFSET: u32 = 8;
+/// offsetof(__wasi_fdstat_t, fs_rights_inheriting)
+const FDSTAT_RIGHTS_INHERITING_OFFSET: u32 = 16;
+/// sizeof(__wasi_filestat_t)
+const FILESTAT_SIZE: u32 = 64;
+const FILESTAT_INO_OFFSET: u32 = 8;
+const FILESTAT_FILETYPE_OFFSET: i32 = 16;
+const FILESTAT_NLINK_OFFSET: u32 = 24;
+const FILESTAT_SIZE_OFFSET: u32 = 32;
+
+const __WASI_FILETYPE_DIRECTORY: i32 = 3;
+const __WASI_FILETYPE_REGULAR_FILE: i32 = 4;
+const __WASI_OFLAGS_CREAT: i32 = 1;
+const __WASI_OFLAGS_DIRECTORY: i32 = 2;
+const __WASI_OFLAGS_TRUNC: i32 = 8;
+const __WASI_WHENCE_CUR: i32 = 1;
+const __WASI_WHENCE_END: i32 = 2;
+
+const __WASI_RIGHTS_FD_READ: i64 = 1 << 1;
+const __WASI_RIGHTS_FD_SEEK: i64 = 1 << 2;
+const __WASI_RIGHTS_FD_TELL: i64 = 1 << 5;
+const __WASI_RIGHTS_FD_WRITE: i64 = 1 << 6;
+const __WASI_RIGHTS_PATH_OPEN: i64 = 1 << 13;
+const __WASI_RIGHTS_FD_FILESTAT_GET: i64 = 1 << 21;
+const FILE_RIGHTS: i64 = __WASI_RIGHTS_FD_READ
+    | __WASI_RIGHTS_FD_SEEK
+    | __WASI_RIGHTS_FD_TELL
+    | __WASI_RIGHTS_FD_FILESTAT_GET;
+const DIR_RIGHTS: i64 = __WASI_RIGHTS_PATH_OPEN;
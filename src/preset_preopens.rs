@@ -0,0 +1,768 @@
+//! Rewriting and filtering the preopened directories reported to a WASI program.
+
+use std::{cell::Cell, ffi::OsString};
+
+use walrus::{
+    ir::{BinaryOp, UnaryOp, Value},
+    FunctionBuilder, FunctionId, GlobalId, InitExpr, InstrSeqBuilder, LocalId, MemoryId, Module,
+    ValType,
+};
+
+use crate::{
+    first_memory, get_import_function, load_i32, load_u8, store_bytes_at, store_i32,
+    string_matches_at, usize_to_wasm_i32, Interposer, __WASI_ERRNO_BADF, __WASI_ERRNO_NAMETOOLONG,
+    PRESTAT_NAME_LEN_OFFSET, PRESTAT_SIZE,
+};
+
+pub struct PresetPreopens {
+    /// (name given by the runtime, name reported to the program)
+    renames: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Names given by the runtime of the preopened directories visible to the program,
+    /// or empty if all of them are visible
+    allowed: Vec<Vec<u8>>,
+    wasi_module_name: String,
+}
+
+/// A name of preopened directories known at instrumentation time
+struct KnownPreopen<'a> {
+    /// Name given by the runtime
+    name: &'a [u8],
+    /// Name reported to the program, if renamed
+    rename: Option<&'a [u8]>,
+    hidden: bool,
+}
+
+impl Default for PresetPreopens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PresetPreopens {
+    pub fn new() -> Self {
+        Self {
+            renames: vec![],
+            allowed: vec![],
+            wasi_module_name: "wasi_snapshot_preview1".to_string(),
+        }
+    }
+
+    /// Report the preopened directory named `from` by the runtime as `to`.
+    /// The first matching rule is used.
+    pub fn rename(&mut self, from: OsString, to: OsString) -> &mut Self {
+        self.renames.push((
+            from.to_string_lossy().as_bytes().to_vec(),
+            to.to_string_lossy().as_bytes().to_vec(),
+        ));
+        self
+    }
+
+    /// Allow the program to see the preopened directory named `name` by the runtime.
+    /// Once any directory is allowed, the others are hidden from the program:
+    /// `fd_prestat_get` reports them as preopens of an unknown type, which wasi-libc skips,
+    /// and `fd_prestat_dir_name` and the functions taking a directory fd, like `path_open`
+    /// and `fd_readdir`, fail with `__WASI_ERRNO_BADF` on them.
+    pub fn allow(&mut self, name: OsString) -> &mut Self {
+        self.allowed
+            .push(name.to_string_lossy().as_bytes().to_vec());
+        self
+    }
+
+    /// The names to look up, in the order of the indices returned by `match_preopen`.
+    fn known_preopens(&self) -> Vec<KnownPreopen<'_>> {
+        let mut known: Vec<KnownPreopen> = vec![];
+        let names = self
+            .renames
+            .iter()
+            .map(|(from, _)| from)
+            .chain(&self.allowed);
+        for name in names {
+            if known.iter().any(|k| k.name == name.as_slice()) {
+                continue;
+            }
+            known.push(KnownPreopen {
+                name,
+                rename: self
+                    .renames
+                    .iter()
+                    .find(|(from, _)| from == name)
+                    .map(|(_, to)| to.as_slice()),
+                hidden: !self.allowed.is_empty() && !self.allowed.contains(name),
+            });
+        }
+        known
+    }
+
+    /// Push whether the preopened directory `matched` by `match_preopen` is hidden.
+    fn push_is_hidden(&self, builder: &mut InstrSeqBuilder, matched: LocalId) {
+        if self.allowed.is_empty() {
+            builder.i32_const(0);
+            return;
+        }
+        // matched == 0 || matched == HIDDEN(0) + 1 || ...
+        builder.local_get(matched).unop(UnaryOp::I32Eqz);
+        for (i, known) in self.known_preopens().iter().enumerate() {
+            if known.hidden {
+                builder
+                    .local_get(matched)
+                    .const_(usize_to_wasm_i32(i + 1))
+                    .binop(BinaryOp::I32Eq)
+                    .binop(BinaryOp::I32Or);
+            }
+        }
+    }
+
+    /// Instrument the input Wasm so that it sees the renamed preopened directories, and
+    /// doesn't see the ones not allowed.
+    ///
+    /// ## Code Shape
+    ///
+    /// Like [`crate::PresetArgs::run`], this adds proxies of `fd_prestat_get` and
+    /// `fd_prestat_dir_name`, and of the functions taking directory fds (`path_*`,
+    /// `fd_readdir` and `fd_renumber`) and `fd_close` if any directory is allowed. They look
+    /// up the name given by the runtime in the known names (the renamed and allowed ones)
+    /// with `$wasi_preset_preopens.match_preopen`, which reads it into a scratch buffer
+    /// grown in the memory on demand. The functions taking directory fds remember the
+    /// result per fd in `$wasi_preset_preopens.is_hidden`, and `fd_close` forgets it:
+    ///
+    /// ```c
+    /// i32 $wasi_preset_preopens.match_preopen(i32 fd) {
+    ///   // $scratch: __wasi_prestat_t followed by the name
+    ///   if ($wasi_snapshot_preview1.fd_prestat_get(fd, $scratch) != __WASI_ERRNO_SUCCESS ||
+    ///       $scratch->tag != __WASI_PREOPENTYPE_DIR) {
+    ///     return -1; // not a preopened directory
+    ///   }
+    ///   size_t len = $scratch->u.dir.pr_name_len;
+    ///   if (sizeof(__wasi_prestat_t) + len > $scratch_capacity) {
+    ///     grow the memory and reallocate $scratch
+    ///   }
+    ///   char *name = $scratch + sizeof(__wasi_prestat_t);
+    ///   if ($wasi_snapshot_preview1.fd_prestat_dir_name(fd, name, len) != __WASI_ERRNO_SUCCESS) {
+    ///     return 0;
+    ///   }
+    ///   if (len == strlen(KNOWN(0)) && memcmp(name, KNOWN(0), len) == 0) return 1;
+    ///   ...
+    ///   return 0;
+    /// }
+    /// i32 $wasi_preset_preopens.fd_prestat_get(i32 fd, __wasi_prestat_t *buf) {
+    ///   i32 err = $wasi_snapshot_preview1.fd_prestat_get(fd, buf);
+    ///   if (err != __WASI_ERRNO_SUCCESS) {
+    ///     return err;
+    ///   }
+    ///   i32 matched = match_preopen(fd);
+    ///   if (IS_HIDDEN(matched)) {
+    ///     buf->tag = PREOPENTYPE_HIDDEN; // not __WASI_PREOPENTYPE_DIR
+    ///     buf->u.dir.pr_name_len = 0;
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   if (matched == i + 1) buf->u.dir.pr_name_len = strlen(TO(i));
+    ///   return __WASI_ERRNO_SUCCESS;
+    /// }
+    /// i32 $wasi_preset_preopens.fd_prestat_dir_name(i32 fd, char *path, size_t path_len) {
+    ///   i32 matched = match_preopen(fd);
+    ///   if (IS_HIDDEN(matched)) return __WASI_ERRNO_BADF;
+    ///   if (matched == i + 1) {
+    ///     if (path_len < strlen(TO(i))) {
+    ///       return __WASI_ERRNO_NAMETOOLONG;
+    ///     }
+    ///     memcpy(path, TO(i), strlen(TO(i)));
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   return $wasi_snapshot_preview1.fd_prestat_dir_name(fd, path, path_len);
+    /// }
+    /// i32 $wasi_preset_preopens.is_hidden(i32 fd) {
+    ///   if (fd < 32 && ($hidden_checked >> fd) & 1) return ($hidden >> fd) & 1;
+    ///   i32 hidden = IS_HIDDEN(match_preopen(fd));
+    ///   if (fd < 32) { remember hidden in $hidden_checked and $hidden; }
+    ///   return hidden;
+    /// }
+    /// // Same for the other functions taking directory fds, checking all of them
+    /// i32 $wasi_preset_preopens.path_open(i32 fd, ...) {
+    ///   if (is_hidden(fd)) return __WASI_ERRNO_BADF;
+    ///   return $wasi_snapshot_preview1.path_open(fd, ...);
+    /// }
+    /// i32 $wasi_preset_preopens.fd_close(i32 fd) {
+    ///   if (fd < 32) $hidden_checked &= ~(1 << fd);
+    ///   return $wasi_snapshot_preview1.fd_close(fd);
+    /// }
+    /// ```
+    ///
+    /// where `IS_HIDDEN(matched)` is true if any directory is allowed and `matched` is 0 or
+    /// the index + 1 of a name not allowed.
+    ///
+    /// ## Limitations
+    ///
+    /// The memory grown for the scratch buffer is never released. The functions taking
+    /// directory fds look up the fds above 31 on every call, which costs two more calls to
+    /// the runtime.
+    ///
+    /// The hidden directories keep their fds, and the `fd_*` functions not listed above, like
+    /// `fd_filestat_get` and `fd_close`, still work on them if the program knows the fds.
+    /// They don't give access to the files in the directories.
+    pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
+        let originals = Originals {
+            fd_prestat_get: get_import_function(module, &self.wasi_module_name, "fd_prestat_get")?,
+            fd_prestat_dir_name: get_import_function(
+                module,
+                &self.wasi_module_name,
+                "fd_prestat_dir_name",
+            )?,
+        };
+        // `match_preopen` calls the original functions, so it's added along with the first
+        // proxy after their uses are replaced.
+        let match_preopen = Cell::new(None);
+        let is_hidden = Cell::new(None);
+        let mut interposer = Interposer::new("wasi_preset_preopens");
+        interposer
+            .interpose(&self.wasi_module_name, "fd_prestat_get", |module, _| {
+                let new_match_preopen = self.add_match_preopen(module, &originals)?;
+                match_preopen.set(Some(new_match_preopen));
+                if !self.allowed.is_empty() {
+                    let cache = HiddenCache::new(module);
+                    is_hidden.set(Some((
+                        self.add_is_hidden(module, cache, new_match_preopen),
+                        cache,
+                    )));
+                }
+                self.add_fd_prestat_get(module, &originals, new_match_preopen)
+            })
+            .interpose(
+                &self.wasi_module_name,
+                "fd_prestat_dir_name",
+                |module, _| {
+                    let match_preopen = match_preopen.get().expect("match_preopen not added");
+                    self.add_fd_prestat_dir_name(module, &originals, match_preopen)
+                },
+            );
+        if !self.allowed.is_empty() {
+            let is_hidden = &is_hidden;
+            // They are imported only by the programs using them, e.g. opening files.
+            for &(name, dir_fds) in DIR_FD_FUNCTIONS {
+                interposer.interpose_if_imported(
+                    &self.wasi_module_name,
+                    name,
+                    move |module, original| {
+                        let (is_hidden, _) = is_hidden.get().expect("is_hidden not added");
+                        Ok(self.add_dir_fd_guard(module, original, name, dir_fds, is_hidden))
+                    },
+                );
+            }
+            interposer.interpose_if_imported(
+                &self.wasi_module_name,
+                "fd_close",
+                move |module, original| {
+                    let (_, cache) = is_hidden.get().expect("is_hidden not added");
+                    Ok(add_fd_close(module, original, cache))
+                },
+            );
+        }
+        interposer.run(module)
+    }
+
+    /// Add `$wasi_preset_preopens.match_preopen (i32 fd) (result i32)` returning the index + 1
+    /// of the known name matching the name of the preopened directory, 0 if none matches or
+    /// the name can't be read, or -1 if `fd` is not a preopened directory.
+    fn add_match_preopen(
+        &self,
+        module: &mut Module,
+        originals: &Originals,
+    ) -> anyhow::Result<FunctionId> {
+        let memory = first_memory(module)?;
+        let mut add_i32_global = || {
+            module
+                .globals
+                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)))
+        };
+        let scratch = add_i32_global();
+        let scratch_capacity = add_i32_global();
+
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder.name("wasi_preset_preopens.match_preopen".to_string());
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        // Locals
+        let len = module.locals.add(ValType::I32);
+        let size = module.locals.add(ValType::I32);
+        let pages = module.locals.add(ValType::I32);
+        let name = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // Room for sizeof(__wasi_prestat_t)
+        body.i32_const(PRESTAT_SIZE).local_set(size);
+        grow_scratch(&mut body, memory, (scratch, scratch_capacity), size, pages);
+        // if (fd_prestat_get(fd, $scratch) != 0 || $scratch->tag != DIR) return -1;
+        body.local_get(fd)
+            .global_get(scratch)
+            .call(originals.fd_prestat_get)
+            .global_get(scratch);
+        load_u8(&mut body, memory, 0)
+            .binop(BinaryOp::I32Or)
+            .if_else(
+                None,
+                |then| {
+                    then.i32_const(-1).return_();
+                },
+                |_| {},
+            );
+        // size_t len = $scratch->u.dir.pr_name_len;
+        body.global_get(scratch)
+            .i32_const(PRESTAT_NAME_LEN_OFFSET)
+            .binop(BinaryOp::I32Add);
+        load_i32(&mut body, memory).local_set(len);
+        // if (len != strlen(KNOWN(0)) && ...) return 0;
+        let known_preopens = self.known_preopens();
+        let mut lengths = known_preopens
+            .iter()
+            .map(|known| known.name.len())
+            .collect::<Vec<_>>();
+        lengths.sort_unstable();
+        lengths.dedup();
+        body.block(None, |known| {
+            let known_id = known.id();
+            for len_candidate in lengths {
+                known
+                    .local_get(len)
+                    .const_(usize_to_wasm_i32(len_candidate))
+                    .binop(BinaryOp::I32Eq)
+                    .br_if(known_id);
+            }
+            known.i32_const(0).return_();
+        });
+        // Room for sizeof(__wasi_prestat_t) + len
+        body.local_get(len)
+            .i32_const(PRESTAT_SIZE)
+            .binop(BinaryOp::I32Add)
+            .local_set(size);
+        grow_scratch(&mut body, memory, (scratch, scratch_capacity), size, pages);
+        // char *name = $scratch + sizeof(__wasi_prestat_t);
+        body.global_get(scratch)
+            .i32_const(PRESTAT_SIZE)
+            .binop(BinaryOp::I32Add)
+            .local_set(name);
+        // The name can't be checked, so treat it as unknown to fail closed.
+        // if (fd_prestat_dir_name(fd, name, len) != 0) return 0;
+        body.local_get(fd)
+            .local_get(name)
+            .local_get(len)
+            .call(originals.fd_prestat_dir_name)
+            .if_else(
+                None,
+                |then| {
+                    then.i32_const(0).return_();
+                },
+                |_| {},
+            );
+        for (i, known) in known_preopens.iter().enumerate() {
+            // if (len == strlen(KNOWN) && memcmp(name, KNOWN, len) == 0) return i + 1;
+            body.local_get(len)
+                .const_(usize_to_wasm_i32(known.name.len()))
+                .binop(BinaryOp::I32Eq);
+            string_matches_at(&mut body, memory, known.name, name, false);
+            body.binop(BinaryOp::I32And).if_else(
+                None,
+                |then| {
+                    then.const_(usize_to_wasm_i32(i + 1)).return_();
+                },
+                |_| {},
+            );
+        }
+        body.i32_const(0);
+        Ok(builder.finish(vec![fd], &mut module.funcs))
+    }
+
+    /// Add `$wasi_preset_preopens.is_hidden (i32 fd) (result i32)` returning whether `fd` is
+    /// a hidden preopened directory, remembered in `cache` for the fds below 32.
+    fn add_is_hidden(
+        &self,
+        module: &mut Module,
+        cache: HiddenCache,
+        match_preopen: FunctionId,
+    ) -> FunctionId {
+        let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        builder.name("wasi_preset_preopens.is_hidden".to_string());
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        // Locals
+        let matched = module.locals.add(ValType::I32);
+        let hidden = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // if (fd < 32 && ($hidden_checked >> fd) & 1) return ($hidden >> fd) & 1;
+        body.local_get(fd)
+            .i32_const(32)
+            .binop(BinaryOp::I32LtU)
+            .global_get(cache.checked)
+            .local_get(fd)
+            .binop(BinaryOp::I32ShrU)
+            .binop(BinaryOp::I32And)
+            .i32_const(1)
+            .binop(BinaryOp::I32And)
+            .if_else(
+                None,
+                |then| {
+                    then.global_get(cache.hidden)
+                        .local_get(fd)
+                        .binop(BinaryOp::I32ShrU)
+                        .i32_const(1)
+                        .binop(BinaryOp::I32And)
+                        .return_();
+                },
+                |_| {},
+            );
+        // i32 hidden = IS_HIDDEN(match_preopen(fd));
+        body.local_get(fd).call(match_preopen).local_set(matched);
+        self.push_is_hidden(&mut body, matched);
+        body.local_set(hidden);
+        // if (fd < 32) {
+        //   $hidden_checked |= 1 << fd;
+        //   $hidden = ($hidden & ~(1 << fd)) | (hidden << fd);
+        // }
+        body.local_get(fd)
+            .i32_const(32)
+            .binop(BinaryOp::I32LtU)
+            .if_else(
+                None,
+                |then| {
+                    then.global_get(cache.checked)
+                        .i32_const(1)
+                        .local_get(fd)
+                        .binop(BinaryOp::I32Shl)
+                        .binop(BinaryOp::I32Or)
+                        .global_set(cache.checked);
+                    then.global_get(cache.hidden)
+                        .i32_const(1)
+                        .local_get(fd)
+                        .binop(BinaryOp::I32Shl)
+                        .i32_const(-1)
+                        .binop(BinaryOp::I32Xor)
+                        .binop(BinaryOp::I32And)
+                        .local_get(hidden)
+                        .local_get(fd)
+                        .binop(BinaryOp::I32Shl)
+                        .binop(BinaryOp::I32Or)
+                        .global_set(cache.hidden);
+                },
+                |_| {},
+            );
+        body.local_get(hidden);
+        builder.finish(vec![fd], &mut module.funcs)
+    }
+
+    fn add_fd_prestat_get(
+        &self,
+        module: &mut Module,
+        originals: &Originals,
+        match_preopen: FunctionId,
+    ) -> anyhow::Result<FunctionId> {
+        let memory = first_memory(module)?;
+        let original = originals.fd_prestat_get;
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_preopens.fd_prestat_get".to_string());
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let buf = module.locals.add(ValType::I32);
+        // Locals
+        let err = module.locals.add(ValType::I32);
+        let matched = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // i32 err = $wasi_snapshot_preview1.fd_prestat_get(fd, buf);
+        body.local_get(fd)
+            .local_get(buf)
+            .call(original)
+            .local_tee(err)
+            .if_else(
+                None,
+                |then| {
+                    then.local_get(err).return_();
+                },
+                |_| {},
+            );
+        body.local_get(fd).call(match_preopen).local_set(matched);
+        if !self.allowed.is_empty() {
+            // Report the hidden ones as preopens of an unknown type instead of failing, as
+            // wasi-libc stops looking for the preopens at the first failing fd.
+            self.push_is_hidden(&mut body, matched);
+            body.if_else(
+                None,
+                |then| {
+                    then.local_get(buf).i32_const(PREOPENTYPE_HIDDEN);
+                    store_i32(then, memory);
+                    then.local_get(buf)
+                        .i32_const(PRESTAT_NAME_LEN_OFFSET)
+                        .binop(BinaryOp::I32Add)
+                        .i32_const(0);
+                    store_i32(then, memory);
+                    then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                },
+                |_| {},
+            );
+        }
+        for (i, known) in self.known_preopens().iter().enumerate() {
+            let to = match known.rename {
+                Some(to) => to,
+                None => continue,
+            };
+            // if (matched == i + 1) buf->u.dir.pr_name_len = strlen(TO(i));
+            body.local_get(matched)
+                .const_(usize_to_wasm_i32(i + 1))
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        then.local_get(buf)
+                            .i32_const(PRESTAT_NAME_LEN_OFFSET)
+                            .binop(BinaryOp::I32Add)
+                            .const_(usize_to_wasm_i32(to.len()));
+                        store_i32(then, memory);
+                    },
+                    |_| {},
+                );
+        }
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        Ok(builder.finish(vec![fd, buf], &mut module.funcs))
+    }
+
+    fn add_fd_prestat_dir_name(
+        &self,
+        module: &mut Module,
+        originals: &Originals,
+        match_preopen: FunctionId,
+    ) -> anyhow::Result<FunctionId> {
+        let memory = first_memory(module)?;
+        let original = originals.fd_prestat_dir_name;
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_preopens.fd_prestat_dir_name".to_string());
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let path = module.locals.add(ValType::I32);
+        let path_len = module.locals.add(ValType::I32);
+        // Locals
+        let matched = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        body.local_get(fd).call(match_preopen).local_set(matched);
+        self.return_badf_if_hidden(&mut body, matched);
+        for (i, known) in self.known_preopens().iter().enumerate() {
+            let to = match known.rename {
+                Some(to) => to,
+                None => continue,
+            };
+            body.local_get(matched)
+                .const_(usize_to_wasm_i32(i + 1))
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        // if (path_len < strlen(TO(i))) return __WASI_ERRNO_NAMETOOLONG;
+                        then.local_get(path_len)
+                            .const_(usize_to_wasm_i32(to.len()))
+                            .binop(BinaryOp::I32LtU)
+                            .if_else(
+                                None,
+                                |then| {
+                                    then.i32_const(__WASI_ERRNO_NAMETOOLONG).return_();
+                                },
+                                |_| {},
+                            );
+                        // memcpy(path, TO(i), strlen(TO(i)));
+                        store_bytes_at(then, memory, to, path, 0);
+                        then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                    },
+                    |_| {},
+                );
+        }
+        // return $wasi_snapshot_preview1.fd_prestat_dir_name(fd, path, path_len);
+        body.local_get(fd)
+            .local_get(path)
+            .local_get(path_len)
+            .call(original);
+        Ok(builder.finish(vec![fd, path, path_len], &mut module.funcs))
+    }
+
+    /// Add a proxy of `name` failing if any of the directory fds at the indices `dir_fds` of
+    /// the parameters is hidden.
+    fn add_dir_fd_guard(
+        &self,
+        module: &mut Module,
+        original: FunctionId,
+        name: &str,
+        dir_fds: &[usize],
+        is_hidden: FunctionId,
+    ) -> FunctionId {
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name(format!("wasi_preset_preopens.{}", name));
+        // Arguments
+        let params = sig
+            .params()
+            .iter()
+            .map(|ty| module.locals.add(*ty))
+            .collect::<Vec<_>>();
+
+        let mut body = builder.func_body();
+        for &i in dir_fds {
+            // if ($wasi_preset_preopens.is_hidden(fd)) return __WASI_ERRNO_BADF;
+            body.local_get(params[i]).call(is_hidden).if_else(
+                None,
+                |then| {
+                    then.i32_const(__WASI_ERRNO_BADF).return_();
+                },
+                |_| {},
+            );
+        }
+        // return $wasi_snapshot_preview1.NAME(...);
+        for param in &params {
+            body.local_get(*param);
+        }
+        body.call(original);
+        builder.finish(params, &mut module.funcs)
+    }
+
+    fn return_badf_if_hidden(&self, builder: &mut InstrSeqBuilder, matched: LocalId) {
+        if self.allowed.is_empty() {
+            return;
+        }
+        self.push_is_hidden(builder, matched);
+        builder.if_else(
+            None,
+            |then| {
+                then.i32_const(__WASI_ERRNO_BADF).return_();
+            },
+            |_| {},
+        );
+    }
+}
+
+struct Originals {
+    fd_prestat_get: FunctionId,
+    fd_prestat_dir_name: FunctionId,
+}
+
+/// Bitsets of the fds below 32 remembering whether they are hidden preopened directories
+#[derive(Clone, Copy)]
+struct HiddenCache {
+    /// The fds already looked up
+    checked: GlobalId,
+    /// The hidden ones among them
+    hidden: GlobalId,
+}
+
+impl HiddenCache {
+    fn new(module: &mut Module) -> Self {
+        let mut add_i32_global = || {
+            module
+                .globals
+                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)))
+        };
+        Self {
+            checked: add_i32_global(),
+            hidden: add_i32_global(),
+        }
+    }
+}
+
+/// Add a proxy of `fd_close` forgetting the fd in `cache`, as its number can be given to
+/// another file.
+fn add_fd_close(module: &mut Module, original: FunctionId, cache: HiddenCache) -> FunctionId {
+    let sig = module.types.get(module.funcs.get(original).ty()).clone();
+    let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+    builder.name("wasi_preset_preopens.fd_close".to_string());
+    // Arguments
+    let fd = module.locals.add(ValType::I32);
+
+    let mut body = builder.func_body();
+    // if (fd < 32) $hidden_checked &= ~(1 << fd);
+    body.local_get(fd)
+        .i32_const(32)
+        .binop(BinaryOp::I32LtU)
+        .if_else(
+            None,
+            |then| {
+                then.global_get(cache.checked)
+                    .i32_const(1)
+                    .local_get(fd)
+                    .binop(BinaryOp::I32Shl)
+                    .i32_const(-1)
+                    .binop(BinaryOp::I32Xor)
+                    .binop(BinaryOp::I32And)
+                    .global_set(cache.checked);
+            },
+            |_| {},
+        );
+    body.local_get(fd).call(original);
+    builder.finish(vec![fd], &mut module.funcs)
+}
+
+/// Grow the memory for the scratch buffer if its capacity is less than `size` bytes.
+/// `size` and `pages` are clobbered, and the function returns 0 if the memory can't grow.
+fn grow_scratch(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    (scratch, capacity): (GlobalId, GlobalId),
+    size: LocalId,
+    pages: LocalId,
+) {
+    // if (size > $scratch_capacity) { ... }
+    builder
+        .local_get(size)
+        .global_get(capacity)
+        .binop(BinaryOp::I32GtU)
+        .if_else(
+            None,
+            |then| {
+                // i32 pages = size / 0x10000 + 1;
+                then.local_get(size)
+                    .i32_const(16)
+                    .binop(BinaryOp::I32ShrU)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_tee(pages)
+                    .memory_grow(memory)
+                    .local_tee(size)
+                    .i32_const(-1)
+                    .binop(BinaryOp::I32Eq)
+                    .if_else(
+                        None,
+                        |then| {
+                            then.i32_const(0).return_();
+                        },
+                        |_| {},
+                    );
+                // $scratch = base * 0x10000; $scratch_capacity = pages * 0x10000;
+                then.local_get(size)
+                    .i32_const(16)
+                    .binop(BinaryOp::I32Shl)
+                    .global_set(scratch);
+                then.local_get(pages)
+                    .i32_const(16)
+                    .binop(BinaryOp::I32Shl)
+                    .global_set(capacity);
+            },
+            |_| {},
+        );
+}
+
+/// The functions taking directory fds, with their indices in the parameters
+const DIR_FD_FUNCTIONS: &[(&str, &[usize])] = &[
+    ("fd_readdir", &[0]),
+    ("fd_renumber", &[0, 1]),
+    ("path_create_directory", &[0]),
+    ("path_filestat_get", &[0]),
+    ("path_filestat_set_times", &[0]),
+    // (old_fd, old_flags, old_path, old_path_len, new_fd, new_path, new_path_len)
+    ("path_link", &[0, 4]),
+    ("path_open", &[0]),
+    ("path_readlink", &[0]),
+    ("path_remove_directory", &[0]),
+    // (fd, old_path, old_path_len, new_fd, new_path, new_path_len)
+    ("path_rename", &[0, 3]),
+    // (old_path, old_path_len, fd, new_path, new_path_len)
+    ("path_symlink", &[2]),
+    ("path_unlink_file", &[0]),
+];
+
+/// Not a `__wasi_preopentype_t`, so that wasi-libc skips the preopen
+const PREOPENTYPE_HIDDEN: i32 = 1;
@@ -0,0 +1,295 @@
+//! Replacing the randomness given to a WASI program with a deterministic one.
+
+use std::ffi::OsString;
+
+use walrus::{
+    ir::{BinaryOp, MemArg, StoreKind, UnaryOp, Value},
+    FunctionBuilder, FunctionId, GlobalId, InitExpr, Module, ValType,
+};
+
+use crate::{
+    expand_env::EnvSnapshot, first_memory, get_or_add_import_function, load_u8, Interposer,
+};
+
+pub struct PresetRandom {
+    seed: u64,
+    /// Name of the environment variable given by the runtime to mix into the seed
+    seed_env: Option<Vec<u8>>,
+    wasi_module_name: String,
+}
+
+impl PresetRandom {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            seed_env: None,
+            wasi_module_name: "wasi_snapshot_preview1".to_string(),
+        }
+    }
+
+    /// Mix the value of the environment variable `name` given by the runtime into the seed,
+    /// so that a run can be reproduced by passing the same value. The seed is used as is
+    /// if the variable is not defined.
+    pub fn seed_from_env(&mut self, name: OsString) -> &mut Self {
+        self.seed_env = Some(name.to_string_lossy().as_bytes().to_vec());
+        self
+    }
+
+    /// Instrument the input Wasm so that `random_get` returns the bytes generated by
+    /// SplitMix64 from the seed.
+    ///
+    /// ## Code Shape
+    ///
+    /// Like [`crate::PresetArgs::run`], this adds a proxy of `random_get`, which never calls
+    /// the original one. The state of the generator is kept in a mutable global:
+    ///
+    /// ```c
+    /// u64 $state = SEED;
+    ///
+    /// u64 $wasi_preset_random.next(void) {
+    ///   u64 z = ($state += 0x9e3779b97f4a7c15);
+    ///   z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9;
+    ///   z = (z ^ (z >> 27)) * 0x94d049bb133111eb;
+    ///   return z ^ (z >> 31);
+    /// }
+    /// i32 $wasi_preset_random.random_get(u8 *buf, size_t buf_len) {
+    ///   // Only if seeded from the environment variable
+    ///   if ($environ == NULL) {
+    ///     // Scratch memory for environ_sizes_get
+    ///     char *scratch = memory.grow(1) * 0x10000;
+    ///     take the snapshot of the environment variables;
+    ///     char *value = getenv(SEED_ENV);
+    ///     if (value != NULL) {
+    ///       $state ^= fnv1a(value);
+    ///     }
+    ///   }
+    ///   u64 word;
+    ///   for (size_t i = 0; i < buf_len; i++) {
+    ///     if (i % 8 == 0) word = $wasi_preset_random.next();
+    ///     buf[i] = (u8)word;
+    ///     word >>= 8;
+    ///   }
+    ///   return __WASI_ERRNO_SUCCESS;
+    /// }
+    /// ```
+    ///
+    /// ## Limitations
+    ///
+    /// The generated bytes are not cryptographically secure. The memory grown for the
+    /// environment variables is never released.
+    pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
+        let state = module.globals.add_local(
+            ValType::I64,
+            true,
+            InitExpr::Value(Value::I64(self.seed as i64)),
+        );
+        let next = add_next(module, state);
+        let mut interposer = Interposer::new("wasi_preset_random");
+        interposer.interpose(&self.wasi_module_name, "random_get", |module, original| {
+            self.add_random_get(module, original, state, next)
+        });
+        interposer.run(module)
+    }
+
+    fn add_random_get(
+        &self,
+        module: &mut Module,
+        original: FunctionId,
+        state: GlobalId,
+        next: FunctionId,
+    ) -> anyhow::Result<FunctionId> {
+        let memory = first_memory(module)?;
+        let seed_env = self.seed_env.as_ref().map(|name| {
+            let environ_functions = (
+                get_or_add_import_function(module, &self.wasi_module_name, "environ_sizes_get"),
+                get_or_add_import_function(module, &self.wasi_module_name, "environ_get"),
+            );
+            (name, environ_functions, EnvSnapshot::new(module))
+        });
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_random.random_get".to_string());
+        // Arguments
+        let buf = module.locals.add(ValType::I32);
+        let buf_len = module.locals.add(ValType::I32);
+        // Locals
+        let i = module.locals.add(ValType::I32);
+        let word = module.locals.add(ValType::I64);
+        let scratch = module.locals.add(ValType::I32);
+        let size_ptr = module.locals.add(ValType::I32);
+        let count = module.locals.add(ValType::I32);
+        let size = module.locals.add(ValType::I32);
+        let err = module.locals.add(ValType::I32);
+        let p = module.locals.add(ValType::I32);
+        let hash = module.locals.add(ValType::I64);
+        let byte = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        if let Some((seed_env, environ_functions, snapshot)) = seed_env {
+            body.global_get(snapshot.environ).if_else(
+                None,
+                |_| {},
+                |else_| {
+                    // char *scratch = memory.grow(1) * 0x10000;
+                    else_
+                        .i32_const(1)
+                        .memory_grow(memory)
+                        .local_tee(scratch)
+                        .i32_const(-1)
+                        .binop(BinaryOp::I32Eq)
+                        .if_else(
+                            None,
+                            |then| {
+                                then.i32_const(crate::__WASI_ERRNO_NOMEM).return_();
+                            },
+                            |_| {},
+                        );
+                    else_
+                        .local_get(scratch)
+                        .i32_const(16)
+                        .binop(BinaryOp::I32Shl)
+                        .local_tee(scratch)
+                        .i32_const(4)
+                        .binop(BinaryOp::I32Add)
+                        .local_set(size_ptr);
+                    snapshot.take(
+                        else_,
+                        memory,
+                        environ_functions,
+                        (scratch, size_ptr),
+                        count,
+                        size,
+                        err,
+                        crate::__WASI_ERRNO_OVERFLOW as u16,
+                    );
+                    // if (value = getenv(SEED_ENV)) $state ^= fnv1a(value);
+                    snapshot.push_getenv(else_, memory, seed_env, count, p);
+                    else_.local_tee(p).if_else(
+                        None,
+                        |then| {
+                            then.i64_const(FNV_OFFSET_BASIS as i64).local_set(hash);
+                            then.loop_(None, |loop_| {
+                                let loop_id = loop_.id();
+                                loop_.local_get(p);
+                                load_u8(loop_, memory, 0).local_tee(byte).if_else(
+                                    None,
+                                    |then| {
+                                        // hash = (hash ^ byte) * FNV_PRIME;
+                                        then.local_get(hash)
+                                            .local_get(byte)
+                                            .unop(UnaryOp::I64ExtendUI32)
+                                            .binop(BinaryOp::I64Xor)
+                                            .i64_const(FNV_PRIME as i64)
+                                            .binop(BinaryOp::I64Mul)
+                                            .local_set(hash);
+                                        then.local_get(p)
+                                            .i32_const(1)
+                                            .binop(BinaryOp::I32Add)
+                                            .local_set(p);
+                                        then.br(loop_id);
+                                    },
+                                    |_| {},
+                                );
+                            });
+                            then.global_get(state)
+                                .local_get(hash)
+                                .binop(BinaryOp::I64Xor)
+                                .global_set(state);
+                        },
+                        |_| {},
+                    );
+                },
+            );
+        }
+        // for (i = 0; i < buf_len; i++) { ... }
+        body.i32_const(0).local_set(i);
+        body.block(None, |done| {
+            let done_id = done.id();
+            done.loop_(None, |loop_| {
+                let loop_id = loop_.id();
+                loop_
+                    .local_get(i)
+                    .local_get(buf_len)
+                    .binop(BinaryOp::I32GeU)
+                    .br_if(done_id);
+                // if (i % 8 == 0) word = next();
+                loop_
+                    .local_get(i)
+                    .i32_const(7)
+                    .binop(BinaryOp::I32And)
+                    .if_else(
+                        None,
+                        |_| {},
+                        |else_| {
+                            else_.call(next).local_set(word);
+                        },
+                    );
+                // buf[i] = (u8)word;
+                loop_
+                    .local_get(buf)
+                    .local_get(i)
+                    .binop(BinaryOp::I32Add)
+                    .local_get(word)
+                    .unop(UnaryOp::I32WrapI64)
+                    .store(
+                        memory,
+                        StoreKind::I32_8 { atomic: false },
+                        MemArg {
+                            align: 1,
+                            offset: 0,
+                        },
+                    );
+                // word >>= 8; i++;
+                loop_
+                    .local_get(word)
+                    .i64_const(8)
+                    .binop(BinaryOp::I64ShrU)
+                    .local_set(word);
+                loop_
+                    .local_get(i)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(i);
+                loop_.br(loop_id);
+            });
+        });
+        body.i32_const(crate::__WASI_ERRNO_SUCCESS);
+        Ok(builder.finish(vec![buf, buf_len], &mut module.funcs))
+    }
+}
+
+/// Add `$wasi_preset_random.next (result i64)` advancing the SplitMix64 generator.
+fn add_next(module: &mut Module, state: GlobalId) -> FunctionId {
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I64]);
+    builder.name("wasi_preset_random.next".to_string());
+    let z = module.locals.add(ValType::I64);
+
+    let mut body = builder.func_body();
+    // u64 z = ($state += 0x9e3779b97f4a7c15);
+    body.global_get(state)
+        .i64_const(0x9e37_79b9_7f4a_7c15_u64 as i64)
+        .binop(BinaryOp::I64Add)
+        .global_set(state);
+    body.global_get(state).local_set(z);
+    for (shift, multiplier) in [(30, 0xbf58_476d_1ce4_e5b9_u64), (27, 0x94d0_49bb_1331_11eb)] {
+        // z = (z ^ (z >> SHIFT)) * MULTIPLIER;
+        body.local_get(z)
+            .local_get(z)
+            .i64_const(shift)
+            .binop(BinaryOp::I64ShrU)
+            .binop(BinaryOp::I64Xor)
+            .i64_const(multiplier as i64)
+            .binop(BinaryOp::I64Mul)
+            .local_set(z);
+    }
+    // return z ^ (z >> 31);
+    body.local_get(z)
+        .local_get(z)
+        .i64_const(31)
+        .binop(BinaryOp::I64ShrU)
+        .binop(BinaryOp::I64Xor);
+    builder.finish(vec![], &mut module.funcs)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
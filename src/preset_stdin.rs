@@ -0,0 +1,248 @@
+//! Serving the data embedded in the module as the stdin of a WASI program.
+
+use walrus::{
+    ir::{BinaryOp, UnaryOp, Value},
+    DataId, DataKind, FunctionBuilder, FunctionId, GlobalId, InitExpr, InstrSeqBuilder, LocalId,
+    MemoryId, Module, ValType,
+};
+
+use crate::{first_memory, load_i32, store_i32, usize_to_wasm_i32, Interposer};
+
+pub struct PresetStdin {
+    data: Vec<u8>,
+    read_host_stdin: bool,
+    wasi_module_name: String,
+}
+
+impl PresetStdin {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            read_host_stdin: false,
+            wasi_module_name: "wasi_snapshot_preview1".to_string(),
+        }
+    }
+
+    /// Read the stdin given by the runtime after the embedded data is exhausted. By default,
+    /// it's ignored and the program reaches EOF at the end of the embedded data.
+    pub fn read_host_stdin(&mut self, read_host_stdin: bool) -> &mut Self {
+        self.read_host_stdin = read_host_stdin;
+        self
+    }
+
+    /// Instrument the input Wasm so that it reads the embedded data from the stdin.
+    ///
+    /// ## Code Shape
+    ///
+    /// Like [`crate::PresetArgs::run`], this adds a proxy of `fd_read`. The data is stored in
+    /// a passive data segment, and the read offset is kept in a mutable global:
+    ///
+    /// ```c
+    /// size_t $offset = 0;
+    ///
+    /// i32 $wasi_preset_stdin.fd_read(i32 fd, const __wasi_iovec_t *iovs, size_t iovs_len,
+    ///                                size_t *nread) {
+    ///   if (fd == 0 && $offset < DATA_LEN) {
+    ///     size_t n = 0;
+    ///     for (size_t i = 0; i < iovs_len && $offset < DATA_LEN; i++) {
+    ///       size_t len = min(iovs[i].buf_len, DATA_LEN - $offset);
+    ///       memory.init(iovs[i].buf, DATA + $offset, len);
+    ///       $offset += len;
+    ///       n += len;
+    ///     }
+    ///     *nread = n;
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   // Unless reading the host stdin after the data
+    ///   if (fd == 0) {
+    ///     *nread = 0;
+    ///     return __WASI_ERRNO_SUCCESS;
+    ///   }
+    ///   return $wasi_snapshot_preview1.fd_read(fd, iovs, iovs_len, nread);
+    /// }
+    /// ```
+    ///
+    /// ## Limitations
+    ///
+    /// The runtime must support the bulk memory operations. Only `fd_read` is interposed,
+    /// so `fd_pread`, `fd_seek` and `poll_oneoff` on the stdin still see the host one.
+    pub fn run(&self, module: &mut Module) -> anyhow::Result<()> {
+        let offset = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+        let mut interposer = Interposer::new("wasi_preset_stdin");
+        interposer.interpose(&self.wasi_module_name, "fd_read", |module, original| {
+            self.add_fd_read(module, original, offset)
+        });
+        interposer.run(module)
+    }
+
+    fn add_fd_read(
+        &self,
+        module: &mut Module,
+        original: FunctionId,
+        offset: GlobalId,
+    ) -> anyhow::Result<FunctionId> {
+        let memory = first_memory(module)?;
+        if self.data.len() > i32::MAX as usize {
+            anyhow::bail!("too large stdin data: {} bytes", self.data.len());
+        }
+        let data = module.data.add(DataKind::Passive, self.data.clone());
+        let data_len = usize_to_wasm_i32(self.data.len());
+        let sig = module.types.get(module.funcs.get(original).ty()).clone();
+        let mut builder = FunctionBuilder::new(&mut module.types, sig.params(), sig.results());
+        builder.name("wasi_preset_stdin.fd_read".to_string());
+        // Arguments
+        let fd = module.locals.add(ValType::I32);
+        let iovs = module.locals.add(ValType::I32);
+        let iovs_len = module.locals.add(ValType::I32);
+        let nread = module.locals.add(ValType::I32);
+        // Locals
+        let n = module.locals.add(ValType::I32);
+        let iov = module.locals.add(ValType::I32);
+        let len = module.locals.add(ValType::I32);
+        let cursor = module.locals.add(ValType::I32);
+
+        let mut body = builder.func_body();
+        // if (fd == 0 && $offset < DATA_LEN) { ... }
+        body.local_get(fd)
+            .unop(UnaryOp::I32Eqz)
+            .global_get(offset)
+            .const_(data_len)
+            .binop(BinaryOp::I32LtU)
+            .binop(BinaryOp::I32And)
+            .if_else(
+                None,
+                |then| {
+                    then.global_get(offset).local_set(cursor);
+                    read_data(
+                        then,
+                        memory,
+                        (data, self.data.len()),
+                        (iovs, iovs_len),
+                        cursor,
+                        n,
+                        (iov, len),
+                    );
+                    then.local_get(cursor).global_set(offset);
+                    // *nread = n;
+                    then.local_get(nread).local_get(n);
+                    store_i32(then, memory);
+                    then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                },
+                |_| {},
+            );
+        if !self.read_host_stdin {
+            // if (fd == 0) { *nread = 0; return __WASI_ERRNO_SUCCESS; }
+            body.local_get(fd).unop(UnaryOp::I32Eqz).if_else(
+                None,
+                |then| {
+                    then.local_get(nread).i32_const(0);
+                    store_i32(then, memory);
+                    then.i32_const(crate::__WASI_ERRNO_SUCCESS).return_();
+                },
+                |_| {},
+            );
+        }
+        // return $wasi_snapshot_preview1.fd_read(fd, iovs, iovs_len, nread);
+        body.local_get(fd)
+            .local_get(iovs)
+            .local_get(iovs_len)
+            .local_get(nread)
+            .call(original);
+        Ok(builder.finish(vec![fd, iovs, iovs_len, nread], &mut module.funcs))
+    }
+}
+
+/// Copy the passive data segment from `offset` into the iovecs like `fd_read`, advancing
+/// `offset` past the copied bytes and setting `n` to the number of them. `iov` and `len` are
+/// clobbered.
+///
+/// ```c
+/// n = 0;
+/// for (iov = iovs; iov < iovs + iovs_len && offset < DATA_LEN; iov++) {
+///   len = min(iov->buf_len, DATA_LEN - offset);
+///   memory.init(iov->buf, DATA + offset, len);
+///   offset += len;
+///   n += len;
+/// }
+/// ```
+pub(crate) fn read_data(
+    builder: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    (data, data_len): (DataId, usize),
+    (iovs, iovs_len): (LocalId, LocalId),
+    offset: LocalId,
+    n: LocalId,
+    (iov, len): (LocalId, LocalId),
+) {
+    let data_len = usize_to_wasm_i32(data_len);
+    builder.i32_const(0).local_set(n);
+    builder.local_get(iovs).local_set(iov);
+    builder.block(None, |done| {
+        let done_id = done.id();
+        done.loop_(None, |loop_| {
+            let loop_id = loop_.id();
+            loop_
+                .local_get(iov)
+                .local_get(iovs)
+                .local_get(iovs_len)
+                .i32_const(IOVEC_SIZE)
+                .binop(BinaryOp::I32Mul)
+                .binop(BinaryOp::I32Add)
+                .binop(BinaryOp::I32GeU)
+                .br_if(done_id);
+            loop_
+                .local_get(offset)
+                .const_(data_len)
+                .binop(BinaryOp::I32GeU)
+                .br_if(done_id);
+            // len = min(iov->buf_len, DATA_LEN - offset);
+            loop_
+                .local_get(iov)
+                .i32_const(IOVEC_BUF_LEN_OFFSET)
+                .binop(BinaryOp::I32Add);
+            load_i32(loop_, memory).local_set(len);
+            loop_
+                .const_(data_len)
+                .local_get(offset)
+                .binop(BinaryOp::I32Sub)
+                .local_get(len)
+                .const_(data_len)
+                .local_get(offset)
+                .binop(BinaryOp::I32Sub)
+                .local_get(len)
+                .binop(BinaryOp::I32LtU)
+                .select(None)
+                .local_set(len);
+            // memory.init(iov->buf, DATA + offset, len);
+            loop_.local_get(iov);
+            load_i32(loop_, memory)
+                .local_get(offset)
+                .local_get(len)
+                .memory_init(memory, data);
+            // offset += len; n += len;
+            loop_
+                .local_get(offset)
+                .local_get(len)
+                .binop(BinaryOp::I32Add)
+                .local_set(offset);
+            loop_
+                .local_get(n)
+                .local_get(len)
+                .binop(BinaryOp::I32Add)
+                .local_set(n);
+            loop_
+                .local_get(iov)
+                .i32_const(IOVEC_SIZE)
+                .binop(BinaryOp::I32Add)
+                .local_set(iov);
+            loop_.br(loop_id);
+        });
+    });
+}
+
+/// sizeof(__wasi_iovec_t)
+const IOVEC_SIZE: i32 = 8;
+/// offsetof(__wasi_iovec_t, buf_len)
+const IOVEC_BUF_LEN_OFFSET: i32 = 4;
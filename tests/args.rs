@@ -0,0 +1,326 @@
+mod common;
+
+use common::{args, Wasi};
+use wasi_preset_args::{
+    ArgPattern, Argv0Policy, FewerArgsPolicy, Placement, PresetArgs, RuntimeArgs,
+    UndefinedEnvPolicy,
+};
+
+fn preset(args: &[&str]) -> PresetArgs {
+    PresetArgs::new(
+        "default.wasm".into(),
+        args.iter().map(|arg| arg.into()).collect(),
+    )
+}
+
+#[test]
+fn simple() {
+    let foo_bar = || preset(&["FOO", "BAR", "XXX"]);
+    assert_eq!(
+        args(|m| foo_bar().run(m), Wasi::new(&["foo_bar.wasm"])).unwrap(),
+        ["foo_bar.wasm", "FOO", "BAR", "XXX"]
+    );
+    assert_eq!(
+        args(
+            |m| foo_bar().run(m),
+            Wasi::new(&["foo_bar.wasm", "FIZZ", "BUZZ"])
+        )
+        .unwrap(),
+        ["foo_bar.wasm", "FOO", "BAR", "XXX", "FIZZ", "BUZZ"]
+    );
+    assert_eq!(
+        args(|m| foo_bar().run(m), Wasi::new(&[])).unwrap(),
+        ["default.wasm", "FOO", "BAR", "XXX"]
+    );
+    let long_str = "FOO_BAR_FIZZ_BUZZ_XXX_YYY_ZZZ";
+    assert_eq!(
+        args(
+            |m| preset(&[long_str]).run(m),
+            Wasi::new(&["long_str.wasm"])
+        )
+        .unwrap(),
+        ["long_str.wasm", long_str]
+    );
+}
+
+#[test]
+fn argv0() {
+    let with_policy =
+        |policy| move |m: &mut walrus::Module| preset(&["FOO"]).argv0_policy(policy).run(m);
+    assert_eq!(
+        args(
+            with_policy(Argv0Policy::AlwaysOverride),
+            Wasi::new(&["override.wasm", "FIZZ"])
+        )
+        .unwrap(),
+        ["default.wasm", "FOO", "FIZZ"]
+    );
+    assert_eq!(
+        args(
+            with_policy(Argv0Policy::Basename),
+            Wasi::new(&["/path/to/tool.wasm", "FIZZ"])
+        )
+        .unwrap(),
+        ["tool.wasm", "FOO", "FIZZ"]
+    );
+    assert_eq!(
+        args(with_policy(Argv0Policy::Basename), Wasi::new(&[])).unwrap(),
+        ["default.wasm", "FOO"]
+    );
+    assert_eq!(
+        args(with_policy(Argv0Policy::KeepRuntime), Wasi::new(&[])).unwrap(),
+        ["", "FOO"]
+    );
+}
+
+#[test]
+fn default_only() {
+    let default_only = |m: &mut walrus::Module| preset(&["FOO", "BAR"]).default_only(true).run(m);
+    assert_eq!(
+        args(default_only, Wasi::new(&["default.wasm"])).unwrap(),
+        ["default.wasm", "FOO", "BAR"]
+    );
+    assert_eq!(
+        args(default_only, Wasi::new(&["default.wasm", "FIZZ", "BUZZ"])).unwrap(),
+        ["default.wasm", "FIZZ", "BUZZ"]
+    );
+}
+
+#[test]
+fn deny() {
+    let deny = |m: &mut walrus::Module| {
+        preset(&["FOO", "BAR"])
+            .deny_runtime_arg("--unsafe-*".parse()?)
+            .deny_runtime_arg(ArgPattern::Exact("--force".into()))
+            .run(m)
+    };
+    assert_eq!(
+        args(
+            deny,
+            Wasi::new(&["deny.wasm", "--unsafe-eval", "file", "--force", "--forced"])
+        )
+        .unwrap(),
+        ["deny.wasm", "FOO", "BAR", "file", "--forced"]
+    );
+    assert_eq!(
+        args(deny, Wasi::new(&["deny.wasm", "--unsafe-eval"])).unwrap(),
+        ["deny.wasm", "FOO", "BAR"]
+    );
+}
+
+#[test]
+fn overridable() {
+    let overridable = |m: &mut walrus::Module| {
+        preset(&["--log-level=info", "--color"])
+            .overridable("--log-level".into())
+            .run(m)
+    };
+    assert_eq!(
+        args(overridable, Wasi::new(&["overridable.wasm", "FIZZ"])).unwrap(),
+        ["overridable.wasm", "--log-level=info", "--color", "FIZZ"]
+    );
+    assert_eq!(
+        args(
+            overridable,
+            Wasi::new(&["overridable.wasm", "--log-level=debug", "FIZZ"])
+        )
+        .unwrap(),
+        ["overridable.wasm", "--color", "--log-level=debug", "FIZZ"]
+    );
+}
+
+#[test]
+fn placement() {
+    let append =
+        |m: &mut walrus::Module| preset(&["FOO", "BAR"]).placement(Placement::Append).run(m);
+    assert_eq!(
+        args(append, Wasi::new(&["append.wasm", "FIZZ", "BUZZ"])).unwrap(),
+        ["append.wasm", "FIZZ", "BUZZ", "FOO", "BAR"]
+    );
+    let after = |policy| {
+        move |m: &mut walrus::Module| {
+            preset(&["FOO", "BAR"])
+                .placement(Placement::After(1))
+                .fewer_args_policy(policy)
+                .run(m)
+        }
+    };
+    assert_eq!(
+        args(
+            after(FewerArgsPolicy::Append),
+            Wasi::new(&["after.wasm", "SUB", "FIZZ", "BUZZ"])
+        )
+        .unwrap(),
+        ["after.wasm", "SUB", "FOO", "BAR", "FIZZ", "BUZZ"]
+    );
+    assert_eq!(
+        args(after(FewerArgsPolicy::Append), Wasi::new(&["after.wasm"])).unwrap(),
+        ["after.wasm", "FOO", "BAR"]
+    );
+    assert_eq!(
+        args(after(FewerArgsPolicy::Skip), Wasi::new(&["after.wasm"])).unwrap(),
+        ["after.wasm"]
+    );
+}
+
+#[test]
+fn sealed() {
+    let sealed = |replace_program_name| {
+        move |m: &mut walrus::Module| {
+            preset(&["FOO", "BAR"])
+                .runtime_args(RuntimeArgs::Sealed {
+                    replace_program_name,
+                })
+                .run(m)
+        }
+    };
+    assert_eq!(
+        args(sealed(false), Wasi::new(&["sealed.wasm", "FIZZ", "BUZZ"])).unwrap(),
+        ["sealed.wasm", "FOO", "BAR"]
+    );
+    assert_eq!(
+        args(sealed(false), Wasi::new(&[])).unwrap(),
+        ["default.wasm", "FOO", "BAR"]
+    );
+    assert_eq!(
+        args(sealed(true), Wasi::new(&["sealed.wasm", "FIZZ", "BUZZ"])).unwrap(),
+        ["default.wasm", "FOO", "BAR"]
+    );
+}
+
+#[test]
+fn separator() {
+    let separator = |m: &mut walrus::Module| preset(&["--foo"]).separator("--".into()).run(m);
+    assert_eq!(
+        args(separator, Wasi::new(&["separator.wasm"])).unwrap(),
+        ["separator.wasm", "--foo"]
+    );
+    assert_eq!(
+        args(separator, Wasi::new(&["separator.wasm", "--bar"])).unwrap(),
+        ["separator.wasm", "--foo", "--", "--bar"]
+    );
+    // The template decides what surrounds the runtime args instead.
+    assert!(common::transform(common::STRINGS_PROGRAM, |m| {
+        preset(&[])
+            .template(vec!["--foo".into(), "$@".into()])
+            .separator("--".into())
+            .run(m)
+    })
+    .is_err());
+}
+
+#[test]
+fn template() {
+    let template = |m: &mut walrus::Module| {
+        preset(&[])
+            .template(vec!["--flag".into(), "$@".into(), "--trailing".into()])
+            .run(m)
+    };
+    assert_eq!(
+        args(template, Wasi::new(&["template.wasm"])).unwrap(),
+        ["template.wasm", "--flag", "--trailing"]
+    );
+    assert_eq!(
+        args(template, Wasi::new(&["template.wasm", "--fizz", "file"])).unwrap(),
+        ["template.wasm", "--flag", "--fizz", "file", "--trailing"]
+    );
+}
+
+#[test]
+fn expand_env() {
+    let expand_env = |undefined| {
+        move |m: &mut walrus::Module| {
+            preset(&["--cache-dir=${HOME}/.cache", "--user=${USER}", "$${HOME}"])
+                .expand_env(undefined)
+                .run(m)
+        }
+    };
+    assert_eq!(
+        args(
+            expand_env(UndefinedEnvPolicy::Empty),
+            Wasi::new(&["empty.wasm", "FIZZ"]).env(&["HOME=/home/me"])
+        )
+        .unwrap(),
+        [
+            "empty.wasm",
+            "--cache-dir=/home/me/.cache",
+            "--user=",
+            "${HOME}",
+            "FIZZ"
+        ]
+    );
+    assert_eq!(
+        args(
+            expand_env(UndefinedEnvPolicy::KeepLiteral),
+            Wasi::new(&["keep.wasm", "FIZZ"]).env(&["HOME=/home/me"])
+        )
+        .unwrap(),
+        [
+            "keep.wasm",
+            "--cache-dir=/home/me/.cache",
+            "--user=${USER}",
+            "${HOME}",
+            "FIZZ"
+        ]
+    );
+}
+
+#[test]
+fn multicall() {
+    let multicall = |m: &mut walrus::Module| {
+        preset(&["FOO"])
+            .multicall("ls".into(), vec!["-l".into()])
+            .multicall("echo".into(), vec!["-n".into(), "hello world".into()])
+            .run(m)
+    };
+    assert_eq!(
+        args(multicall, Wasi::new(&["/bin/ls", "FIZZ"])).unwrap(),
+        ["/bin/ls", "-l", "FIZZ"]
+    );
+    assert_eq!(
+        args(multicall, Wasi::new(&["unknown", "FIZZ"])).unwrap(),
+        ["unknown", "FOO", "FIZZ"]
+    );
+    assert_eq!(
+        args(multicall, Wasi::new(&["echo"])).unwrap(),
+        ["echo", "-n", "hello world"]
+    );
+}
+
+#[test]
+fn limits() {
+    let limits = |m: &mut walrus::Module| {
+        preset(&["FOO", "BAR"])
+            .max_runtime_argc(2)
+            .max_argv_buf_size(45)
+            .run(m)
+    };
+    assert_eq!(
+        args(limits, Wasi::new(&["limits.wasm", "FIZZ", "BUZZ"])).unwrap(),
+        ["limits.wasm", "FOO", "BAR", "FIZZ", "BUZZ"]
+    );
+    // __WASI_ERRNO_2BIG
+    assert_eq!(
+        args(limits, Wasi::new(&["limits.wasm", "FIZZ", "BUZZ", "FOO"])),
+        Err(1)
+    );
+    assert_eq!(
+        args(limits, Wasi::new(&["limits.wasm", "FIZZBUZZFIZZBUZZ"])),
+        Err(1)
+    );
+}
+
+#[test]
+fn too_many_overridable_keys() {
+    let overridable = |keys: usize| {
+        common::transform(common::STRINGS_PROGRAM, |m| {
+            let mut preset = preset(&[]);
+            for i in 0..keys {
+                preset.overridable(format!("--key{}", i).into());
+            }
+            preset.run(m)
+        })
+    };
+    assert!(overridable(32).is_ok());
+    assert!(overridable(33).is_err());
+}
@@ -0,0 +1,397 @@
+//! A minimal WASI host to run the transformed modules without a wasm toolchain or runtime.
+//!
+//! Each test crate uses a different part of the helpers.
+#![allow(dead_code)]
+
+use wasmi::core::Trap;
+use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+pub const WASI: &str = "wasi_snapshot_preview1";
+
+pub const __WASI_ERRNO_BADF: i32 = 8;
+pub const __WASI_ERRNO_FAULT: i32 = 21;
+
+/// A program that reads the args and the environment variables with the usual two calls.
+/// `args` and `environ` store the count at 0 and the buffer size at 4, and the pointers and
+/// the strings at the given addresses.
+pub const STRINGS_PROGRAM: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "args_sizes_get" (func $args_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_get" (func $args_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_sizes_get" (func $environ_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_get" (func $environ_get (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "args") (param $vec i32) (param $buf i32) (result i32)
+    (local $errno i32)
+    (local.set $errno (call $args_sizes_get (i32.const 0) (i32.const 4)))
+    (if (local.get $errno) (then (return (local.get $errno))))
+    (call $args_get (local.get $vec) (local.get $buf)))
+  (func (export "environ") (param $vec i32) (param $buf i32) (result i32)
+    (local $errno i32)
+    (local.set $errno (call $environ_sizes_get (i32.const 0) (i32.const 4)))
+    (if (local.get $errno) (then (return (local.get $errno))))
+    (call $environ_get (local.get $vec) (local.get $buf)))
+)
+"#;
+
+/// Where [`STRINGS_PROGRAM`] is asked to put the pointers and the strings. The buffer is
+/// unaligned like the one from `malloc(size)` can be.
+const VEC: usize = 1024;
+const BUF: usize = 4097;
+
+/// The state of the host seen by the program.
+#[derive(Debug, Default)]
+pub struct Wasi {
+    pub args: Vec<Vec<u8>>,
+    pub env: Vec<Vec<u8>>,
+    /// The names of the preopened directories, numbered from fd 3
+    pub preopens: Vec<Vec<u8>>,
+    /// The errno returned by `fd_prestat_dir_name` instead of the names, if any
+    pub dir_name_errno: Option<i32>,
+    /// The fds opened by `path_open`, numbered from the first one after the preopens
+    pub opened: Vec<i32>,
+    /// The WASI functions called by the program, in order
+    pub calls: Vec<String>,
+}
+
+impl Wasi {
+    pub fn new(args: &[&str]) -> Self {
+        Self {
+            args: args.iter().map(|arg| arg.as_bytes().to_vec()).collect(),
+            ..Self::default()
+        }
+    }
+
+    pub fn env(mut self, env: &[&str]) -> Self {
+        self.env = env.iter().map(|var| var.as_bytes().to_vec()).collect();
+        self
+    }
+
+    pub fn preopens(mut self, names: &[&str]) -> Self {
+        self.preopens = names.iter().map(|name| name.as_bytes().to_vec()).collect();
+        self
+    }
+}
+
+/// Transform the module written in the text format, and check that the output is still
+/// a valid module.
+pub fn transform<F>(wat: &str, preset: F) -> anyhow::Result<Vec<u8>>
+where
+    F: FnOnce(&mut walrus::Module) -> anyhow::Result<()>,
+{
+    let mut module = walrus::Module::from_buffer(&wat::parse_str(wat)?)?;
+    preset(&mut module)?;
+    let wasm = module.emit_wasm();
+    walrus::Module::from_buffer(&wasm)?;
+    Ok(wasm)
+}
+
+pub struct Instance {
+    store: Store<Wasi>,
+    instance: wasmi::Instance,
+}
+
+impl Instance {
+    pub fn new(wasm: &[u8], wasi: Wasi) -> Self {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).expect("the module is valid");
+        let mut linker = <Linker<Wasi>>::new(&engine);
+        define_wasi(&mut linker);
+        // Trap on the functions the mock doesn't implement, so that a test notices an
+        // unexpected call instead of an instantiation failure
+        for import in module.imports() {
+            if import.module() == WASI && IMPLEMENTED.contains(&import.name()) {
+                continue;
+            }
+            let ty = import
+                .ty()
+                .func()
+                .expect("only functions are imported")
+                .clone();
+            let message = format!("{}::{} is not implemented", import.module(), import.name());
+            linker
+                .func_new(import.module(), import.name(), ty, move |_, _, _| {
+                    Err(Trap::new(message.clone()))
+                })
+                .unwrap();
+        }
+        let mut store = Store::new(&engine, wasi);
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .expect("the module is instantiated");
+        Self { store, instance }
+    }
+
+    /// Call an exported `(param i32 i32) (result i32)` function.
+    pub fn call(&mut self, name: &str, params: (i32, i32)) -> Result<i32, wasmi::Error> {
+        let func = self
+            .instance
+            .get_typed_func::<(i32, i32), i32>(&self.store, name)?;
+        Ok(func.call(&mut self.store, params)?)
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        let memory = self
+            .instance
+            .get_memory(&self.store, "memory")
+            .expect("memory is exported");
+        memory.data(&self.store)
+    }
+
+    pub fn wasi(&self) -> &Wasi {
+        self.store.data()
+    }
+
+    /// Call `args` or `environ` of [`STRINGS_PROGRAM`] and read the strings, checking that
+    /// they are written within the sizes reported to the program.
+    pub fn strings(&mut self, name: &str) -> Result<Vec<String>, i32> {
+        let errno = self.call(name, (VEC as i32, BUF as i32)).unwrap();
+        if errno != 0 {
+            return Err(errno);
+        }
+        let memory = self.memory();
+        let count = load_u32(memory, 0) as usize;
+        let size = load_u32(memory, 4) as usize;
+        let buf = BUF..BUF + size;
+        let strings = (0..count)
+            .map(|i| {
+                let ptr = load_u32(memory, VEC + i * 4) as usize;
+                let len = memory[ptr..].iter().position(|&b| b == 0).unwrap();
+                assert!(
+                    buf.contains(&ptr) && ptr + len < buf.end,
+                    "{} is out of the buffer",
+                    i
+                );
+                String::from_utf8(memory[ptr..ptr + len].to_vec()).unwrap()
+            })
+            .collect();
+        assert!(
+            memory[VEC + count * 4..VEC + count * 4 + 16]
+                .iter()
+                .all(|&b| b == 0),
+            "written after the pointers"
+        );
+        assert!(
+            memory[buf.end..buf.end + 16].iter().all(|&b| b == 0),
+            "written after the buffer"
+        );
+        Ok(strings)
+    }
+}
+
+/// Run [`STRINGS_PROGRAM`] transformed by `preset` and return the args it sees.
+pub fn args<F>(preset: F, wasi: Wasi) -> Result<Vec<String>, i32>
+where
+    F: FnOnce(&mut walrus::Module) -> anyhow::Result<()>,
+{
+    let wasm = transform(STRINGS_PROGRAM, preset).unwrap();
+    Instance::new(&wasm, wasi).strings("args")
+}
+
+/// Run [`STRINGS_PROGRAM`] transformed by `preset` and return the environment variables it sees.
+pub fn environ<F>(preset: F, wasi: Wasi) -> Result<Vec<String>, i32>
+where
+    F: FnOnce(&mut walrus::Module) -> anyhow::Result<()>,
+{
+    let wasm = transform(STRINGS_PROGRAM, preset).unwrap();
+    Instance::new(&wasm, wasi).strings("environ")
+}
+
+pub fn load_u32(memory: &[u8], addr: usize) -> u32 {
+    u32::from_le_bytes(memory[addr..addr + 4].try_into().unwrap())
+}
+
+fn store(memory: &mut [u8], addr: i32, bytes: &[u8]) -> Result<(), i32> {
+    let addr = addr as u32 as usize;
+    memory
+        .get_mut(addr..addr + bytes.len())
+        .ok_or(__WASI_ERRNO_FAULT)?
+        .copy_from_slice(bytes);
+    Ok(())
+}
+
+fn memory_and_wasi<'a>(caller: &'a mut Caller<'_, Wasi>) -> (&'a mut [u8], &'a mut Wasi) {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .expect("memory is exported");
+    memory.data_and_store_mut(caller)
+}
+
+fn errno(result: Result<(), i32>) -> i32 {
+    result.err().unwrap_or(0)
+}
+
+fn sizes_get(memory: &mut [u8], strings: &[Vec<u8>], count: i32, size: i32) -> Result<(), i32> {
+    let total = strings.iter().map(|s| s.len() + 1).sum::<usize>();
+    store(memory, count, &(strings.len() as u32).to_le_bytes())?;
+    store(memory, size, &(total as u32).to_le_bytes())
+}
+
+/// Write the strings like wasmtime, which rejects an unaligned pointer vector.
+fn strings_get(memory: &mut [u8], strings: &[Vec<u8>], vec: i32, buf: i32) -> Result<i32, Trap> {
+    if vec % 4 != 0 {
+        return Err(Trap::new(format!("unaligned pointer vector at {}", vec)));
+    }
+    let mut ptr = buf;
+    Ok(errno(strings.iter().enumerate().try_for_each(|(i, s)| {
+        store(memory, vec + i as i32 * 4, &ptr.to_le_bytes())?;
+        store(memory, ptr, s)?;
+        store(memory, ptr + s.len() as i32, &[0])?;
+        ptr += s.len() as i32 + 1;
+        Ok(())
+    })))
+}
+
+const IMPLEMENTED: &[&str] = &[
+    "args_sizes_get",
+    "args_get",
+    "environ_sizes_get",
+    "environ_get",
+    "fd_prestat_get",
+    "fd_prestat_dir_name",
+    "path_open",
+    "fd_close",
+    "fd_sync",
+    "proc_exit",
+];
+
+fn define_wasi(linker: &mut Linker<Wasi>) {
+    linker
+        .func_wrap(
+            WASI,
+            "args_sizes_get",
+            |mut caller: Caller<'_, Wasi>, count: i32, size: i32| {
+                let (memory, wasi) = memory_and_wasi(&mut caller);
+                wasi.calls.push("args_sizes_get".to_string());
+                errno(sizes_get(memory, &wasi.args, count, size))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            WASI,
+            "args_get",
+            |mut caller: Caller<'_, Wasi>, vec: i32, buf: i32| {
+                let (memory, wasi) = memory_and_wasi(&mut caller);
+                wasi.calls.push("args_get".to_string());
+                strings_get(memory, &wasi.args, vec, buf)
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            WASI,
+            "environ_sizes_get",
+            |mut caller: Caller<'_, Wasi>, count: i32, size: i32| {
+                let (memory, wasi) = memory_and_wasi(&mut caller);
+                wasi.calls.push("environ_sizes_get".to_string());
+                errno(sizes_get(memory, &wasi.env, count, size))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            WASI,
+            "environ_get",
+            |mut caller: Caller<'_, Wasi>, vec: i32, buf: i32| {
+                let (memory, wasi) = memory_and_wasi(&mut caller);
+                wasi.calls.push("environ_get".to_string());
+                strings_get(memory, &wasi.env, vec, buf)
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            WASI,
+            "fd_prestat_get",
+            |mut caller: Caller<'_, Wasi>, fd: i32, prestat: i32| {
+                let (memory, wasi) = memory_and_wasi(&mut caller);
+                wasi.calls.push("fd_prestat_get".to_string());
+                let name = match wasi.preopens.get((fd as usize).wrapping_sub(3)) {
+                    Some(name) => name,
+                    None => return __WASI_ERRNO_BADF,
+                };
+                // __WASI_PREOPENTYPE_DIR and the name length
+                errno(
+                    store(memory, prestat, &0u32.to_le_bytes()).and_then(|_| {
+                        store(memory, prestat + 4, &(name.len() as u32).to_le_bytes())
+                    }),
+                )
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            WASI,
+            "fd_prestat_dir_name",
+            |mut caller: Caller<'_, Wasi>, fd: i32, path: i32, len: i32| {
+                let (memory, wasi) = memory_and_wasi(&mut caller);
+                wasi.calls.push("fd_prestat_dir_name".to_string());
+                if let Some(errno) = wasi.dir_name_errno {
+                    return errno;
+                }
+                let name = match wasi.preopens.get((fd as usize).wrapping_sub(3)) {
+                    Some(name) => name,
+                    None => return __WASI_ERRNO_BADF,
+                };
+                let len = (len as usize).min(name.len());
+                errno(store(memory, path, &name[..len]))
+            },
+        )
+        .unwrap()
+        .func_wrap(
+            WASI,
+            "path_open",
+            // Open nothing but check the directory fd, and give the lowest free fd
+            |mut caller: Caller<'_, Wasi>,
+             fd: i32,
+             _dirflags: i32,
+             _path: i32,
+             _path_len: i32,
+             _oflags: i32,
+             _rights_base: i64,
+             _rights_inheriting: i64,
+             _fdflags: i32,
+             opened_fd: i32| {
+                let (memory, wasi) = memory_and_wasi(&mut caller);
+                wasi.calls.push("path_open".to_string());
+                if wasi.preopens.get((fd as usize).wrapping_sub(3)).is_none() {
+                    return __WASI_ERRNO_BADF;
+                }
+                let opened = (3 + wasi.preopens.len() as i32..)
+                    .find(|fd| !wasi.opened.contains(fd))
+                    .unwrap();
+                wasi.opened.push(opened);
+                errno(store(memory, opened_fd, &opened.to_le_bytes()))
+            },
+        )
+        .unwrap()
+        .func_wrap(WASI, "fd_close", |mut caller: Caller<'_, Wasi>, fd: i32| {
+            let wasi = caller.data_mut();
+            wasi.calls.push(format!("fd_close({})", fd));
+            match wasi.opened.iter().position(|opened| *opened == fd) {
+                Some(i) => {
+                    wasi.opened.remove(i);
+                    0
+                }
+                None => __WASI_ERRNO_BADF,
+            }
+        })
+        .unwrap()
+        .func_wrap(WASI, "fd_sync", |mut caller: Caller<'_, Wasi>, fd: i32| {
+            let wasi = caller.data_mut();
+            wasi.calls.push(format!("fd_sync({})", fd));
+            if wasi.opened.contains(&fd) {
+                0
+            } else {
+                __WASI_ERRNO_BADF
+            }
+        })
+        .unwrap()
+        .func_wrap(
+            WASI,
+            "proc_exit",
+            |mut caller: Caller<'_, Wasi>, code: i32| -> Result<(), Trap> {
+                caller.data_mut().calls.push("proc_exit".to_string());
+                Err(Trap::i32_exit(code))
+            },
+        )
+        .unwrap();
+}
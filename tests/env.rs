@@ -0,0 +1,99 @@
+mod common;
+
+use common::{environ, transform, Instance, Wasi, STRINGS_PROGRAM};
+use wasi_preset_args::{EnvPrecedence, PresetEnv};
+
+fn preset(vars: &[(&str, &str)]) -> PresetEnv {
+    PresetEnv::new(
+        vars.iter()
+            .map(|(key, value)| (key.into(), value.into()))
+            .collect(),
+    )
+}
+
+#[test]
+fn precedence() {
+    let host = Wasi::new(&[]).env(&["FOO=host", "BAR=1"]);
+    assert_eq!(
+        environ(|m| preset(&[("FOO", "preset")]).run(m), host).unwrap(),
+        ["FOO=preset", "BAR=1"]
+    );
+    let host_precedence = |m: &mut walrus::Module| {
+        preset(&[("FOO", "preset"), ("BAZ", "preset")])
+            .precedence("FOO".into(), EnvPrecedence::Host)?
+            .precedence("BAZ".into(), EnvPrecedence::Host)?
+            .run(m)
+    };
+    let host = Wasi::new(&[]).env(&["FOO=host", "BAR=1"]);
+    assert_eq!(
+        environ(host_precedence, host).unwrap(),
+        ["BAZ=preset", "FOO=host", "BAR=1"]
+    );
+    let error = |m: &mut walrus::Module| {
+        preset(&[("FOO", "preset")])
+            .precedence("FOO".into(), EnvPrecedence::Error)?
+            .run(m)
+    };
+    // __WASI_ERRNO_INVAL
+    assert_eq!(environ(error, Wasi::new(&[]).env(&["FOO=host"])), Err(28));
+    assert_eq!(
+        environ(error, Wasi::new(&[]).env(&["BAR=1"])).unwrap(),
+        ["FOO=preset", "BAR=1"]
+    );
+}
+
+#[test]
+fn filter() {
+    let filter = |m: &mut walrus::Module| {
+        preset(&[])
+            .allow_host_var("LC_*".parse()?)
+            .allow_host_var("HOME".parse()?)
+            .deny_host_var("LC_SECRET".parse()?)
+            .run(m)
+    };
+    let host = Wasi::new(&[]).env(&["HOME=/home/me", "HOMEDIR=/", "LC_ALL=C", "LC_SECRET=1"]);
+    assert_eq!(
+        environ(filter, host).unwrap(),
+        ["HOME=/home/me", "LC_ALL=C"]
+    );
+}
+
+#[test]
+fn snapshot_once() {
+    let wasm = transform(STRINGS_PROGRAM, |m| preset(&[("FOO", "preset")]).run(m)).unwrap();
+    let mut instance = Instance::new(&wasm, Wasi::new(&[]).env(&["FOO=host", "BAR=1"]));
+    let expected = ["FOO=preset", "BAR=1"];
+    assert_eq!(instance.strings("environ").unwrap(), expected);
+    let memory_size = instance.memory().len();
+    // The snapshot taken by the first call is reused
+    assert_eq!(instance.strings("environ").unwrap(), expected);
+    assert_eq!(instance.memory().len(), memory_size);
+    let host_calls = instance.wasi().calls.iter();
+    assert_eq!(host_calls.filter(|call| *call == "environ_get").count(), 1);
+}
+
+#[test]
+fn too_many_host_precedence_vars() {
+    let host_precedence = |vars: usize| {
+        transform(STRINGS_PROGRAM, |m| {
+            let keys = (0..vars).map(|i| format!("KEY{}", i)).collect::<Vec<_>>();
+            let mut preset = preset(
+                &keys
+                    .iter()
+                    .map(|key| (key.as_str(), "1"))
+                    .collect::<Vec<_>>(),
+            );
+            for key in &keys {
+                preset.precedence(key.into(), EnvPrecedence::Host)?;
+            }
+            preset.run(m)
+        })
+    };
+    // The last one takes the sign bit of the i32 bitset
+    let wasm = host_precedence(32).unwrap();
+    let mut instance = Instance::new(&wasm, Wasi::new(&[]).env(&["KEY31=host"]));
+    let vars = instance.strings("environ").unwrap();
+    assert_eq!(vars.len(), 32);
+    assert_eq!(vars[30..], ["KEY30=1", "KEY31=host"]);
+    assert!(host_precedence(33).is_err());
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use common::{load_u32, transform, Instance, Wasi, __WASI_ERRNO_BADF};
+use wasi_preset_args::PresetFiles;
+
+/// A program that opens the path at 0 relative to a directory fd and uses the opened fd.
+/// `open` stores the opened fd at 64, and `read` stores the content read at 256 and its
+/// length at 136.
+const FILES_PROGRAM: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "fd_prestat_get" (func $fd_prestat_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_read" (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_sync" (func $fd_sync (param i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "app.toml")
+  (data (i32.const 128) "\00\01\00\00\40\00\00\00")
+  (func (export "prestat") (param $fd i32) (param $buf i32) (result i32)
+    (call $fd_prestat_get (local.get $fd) (local.get $buf)))
+  (func (export "open") (param $fd i32) (param $path_len i32) (result i32)
+    (call $path_open (local.get $fd) (i32.const 0) (i32.const 0) (local.get $path_len) (i32.const 0)
+      (i64.const 0) (i64.const 0) (i32.const 0) (i32.const 64)))
+  (func (export "read") (param $fd i32) (param $unused i32) (result i32)
+    (call $fd_read (local.get $fd) (i32.const 128) (i32.const 1) (i32.const 136)))
+  (func (export "close") (param $fd i32) (param $unused i32) (result i32)
+    (call $fd_close (local.get $fd)))
+  (func (export "sync") (param $fd i32) (param $unused i32) (result i32)
+    (call $fd_sync (local.get $fd)))
+)
+"#;
+
+const PATH_LEN: i32 = "app.toml".len() as i32;
+
+fn open(instance: &mut Instance, fd: i32, path_len: i32) -> Result<i32, i32> {
+    match instance.call("open", (fd, path_len)).unwrap() {
+        0 => Ok(load_u32(instance.memory(), 64) as i32),
+        errno => Err(errno),
+    }
+}
+
+fn instantiate(wasi: Wasi) -> Instance {
+    let wasm = transform(FILES_PROGRAM, |m| {
+        PresetFiles::new("/embedded".into())
+            .file("app.toml".into(), b"name = \"app\"\n".to_vec())
+            .run(m)
+    })
+    .unwrap();
+    Instance::new(&wasm, wasi)
+}
+
+#[test]
+fn embedded() {
+    let mut instance = instantiate(Wasi::new(&[]).preopens(&["/tmp"]));
+    assert_eq!(instance.call("prestat", (3, 1024)).unwrap(), 0);
+    // Reported after the preopens of the runtime
+    assert_eq!(instance.call("prestat", (4, 1024)).unwrap(), 0);
+    assert_eq!(load_u32(instance.memory(), 1024 + 4), 9);
+    assert_eq!(
+        instance.call("prestat", (5, 1024)).unwrap(),
+        __WASI_ERRNO_BADF
+    );
+    let fd = open(&mut instance, 4, PATH_LEN).unwrap();
+    assert_eq!(instance.call("read", (fd, 0)).unwrap(), 0);
+    let len = load_u32(instance.memory(), 136) as usize;
+    assert_eq!(&instance.memory()[256..256 + len], b"name = \"app\"\n");
+    assert_eq!(instance.call("close", (fd, 0)).unwrap(), 0);
+    assert_eq!(instance.call("read", (fd, 0)).unwrap(), __WASI_ERRNO_BADF);
+    assert!(instance
+        .wasi()
+        .calls
+        .iter()
+        .all(|call| call == "fd_prestat_get"));
+}
+
+#[test]
+fn runtime_fd_numbered_as_dir() {
+    let mut instance = instantiate(Wasi::new(&[]).preopens(&["/tmp"]));
+    assert_eq!(instance.call("prestat", (4, 1024)).unwrap(), 0);
+    // The runtime gives the number of the virtual directory to the file, which the program
+    // sees under another number
+    let fd = open(&mut instance, 3, PATH_LEN).unwrap();
+    assert_eq!(instance.wasi().opened, [4]);
+    assert_ne!(fd, 4);
+    assert_eq!(instance.call("sync", (fd, 0)).unwrap(), 0);
+    // The virtual directory is still there, and doesn't reach the runtime
+    assert!(open(&mut instance, 4, PATH_LEN).is_ok());
+    assert_eq!(instance.call("sync", (4, 0)).unwrap(), __WASI_ERRNO_BADF);
+    assert_eq!(instance.call("close", (4, 0)).unwrap(), __WASI_ERRNO_BADF);
+    assert_eq!(instance.call("close", (fd, 0)).unwrap(), 0);
+    assert_eq!(instance.wasi().opened, []);
+    assert_eq!(
+        instance.wasi().calls[1..],
+        ["path_open", "fd_sync(4)", "fd_close(4)"]
+    );
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{load_u32, transform, Instance, Wasi, __WASI_ERRNO_BADF};
+use wasi_preset_args::PresetPreopens;
+
+/// A program that calls the functions taking a preopened directory fd, with the results
+/// stored at the given addresses.
+const PREOPENS_PROGRAM: &str = r#"
+(module
+  (import "wasi_snapshot_preview1" "fd_prestat_get" (func $fd_prestat_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_prestat_dir_name" (func $fd_prestat_dir_name (param i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "path_open" (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+  (memory (export "memory") 1)
+  (func (export "prestat") (param $fd i32) (param $buf i32) (result i32)
+    (call $fd_prestat_get (local.get $fd) (local.get $buf)))
+  (func (export "dir_name") (param $fd i32) (param $buf i32) (result i32)
+    (call $fd_prestat_dir_name (local.get $fd) (local.get $buf) (i32.const 64)))
+  (func (export "open") (param $fd i32) (param $opened_fd i32) (result i32)
+    (call $path_open (local.get $fd) (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0)
+      (i64.const 0) (i64.const 0) (i32.const 0) (local.get $opened_fd)))
+  (func (export "close") (param $fd i32) (param $unused i32) (result i32)
+    (call $fd_close (local.get $fd)))
+)
+"#;
+
+const BUF: i32 = 1024;
+
+/// The tag and the name length reported by `fd_prestat_get`
+fn prestat(instance: &mut Instance, fd: i32) -> Result<(u32, u32), i32> {
+    match instance.call("prestat", (fd, BUF)).unwrap() {
+        0 => Ok((
+            load_u32(instance.memory(), BUF as usize),
+            load_u32(instance.memory(), BUF as usize + 4),
+        )),
+        errno => Err(errno),
+    }
+}
+
+fn dir_name(instance: &mut Instance, fd: i32, len: usize) -> Result<String, i32> {
+    match instance.call("dir_name", (fd, BUF)).unwrap() {
+        0 => {
+            let name = &instance.memory()[BUF as usize..BUF as usize + len];
+            Ok(String::from_utf8(name.to_vec()).unwrap())
+        }
+        errno => Err(errno),
+    }
+}
+
+fn instantiate<F>(preset: F, wasi: Wasi) -> Instance
+where
+    F: FnOnce(&mut PresetPreopens) -> &mut PresetPreopens,
+{
+    let wasm = transform(PREOPENS_PROGRAM, |m| {
+        preset(&mut PresetPreopens::new()).run(m)
+    })
+    .unwrap();
+    Instance::new(&wasm, wasi)
+}
+
+#[test]
+fn rename() {
+    let mut instance = instantiate(
+        |preopens| preopens.rename("/host/data".into(), "/data".into()),
+        Wasi::new(&[]).preopens(&["/host/data", "/tmp"]),
+    );
+    assert_eq!(prestat(&mut instance, 3), Ok((0, 5)));
+    assert_eq!(dir_name(&mut instance, 3, 5).as_deref(), Ok("/data"));
+    assert_eq!(prestat(&mut instance, 4), Ok((0, 4)));
+    assert_eq!(dir_name(&mut instance, 4, 4).as_deref(), Ok("/tmp"));
+    assert_eq!(prestat(&mut instance, 5), Err(__WASI_ERRNO_BADF));
+}
+
+#[test]
+fn hidden() {
+    let mut instance = instantiate(
+        |preopens| preopens.allow("/tmp".into()),
+        Wasi::new(&[]).preopens(&["/secret", "/tmp"]),
+    );
+    // Reported with an unknown tag, so that the scan goes on to the next fd
+    assert_eq!(prestat(&mut instance, 3), Ok((1, 0)));
+    assert_eq!(dir_name(&mut instance, 3, 0), Err(__WASI_ERRNO_BADF));
+    assert_eq!(instance.call("open", (3, BUF)).unwrap(), __WASI_ERRNO_BADF);
+    assert_eq!(prestat(&mut instance, 4), Ok((0, 4)));
+    assert_eq!(instance.call("open", (4, BUF)).unwrap(), 0);
+    // Only the allowed one reaches the host
+    let opened = instance
+        .wasi()
+        .calls
+        .iter()
+        .filter(|call| *call == "path_open");
+    assert_eq!(opened.count(), 1);
+}
+
+#[test]
+fn hidden_without_name() {
+    // The name can't be checked against the allowed ones
+    let mut wasi = Wasi::new(&[]).preopens(&["/tmp"]);
+    wasi.dir_name_errno = Some(__WASI_ERRNO_BADF);
+    let mut instance = instantiate(|preopens| preopens.allow("/tmp".into()), wasi);
+    assert_eq!(prestat(&mut instance, 3), Ok((1, 0)));
+    assert_eq!(instance.call("open", (3, BUF)).unwrap(), __WASI_ERRNO_BADF);
+}
+
+#[test]
+fn hidden_cached() {
+    let mut instance = instantiate(
+        |preopens| preopens.allow("/tmp".into()),
+        Wasi::new(&[]).preopens(&["/secret", "/tmp"]),
+    );
+    let lookups = |instance: &Instance| {
+        instance
+            .wasi()
+            .calls
+            .iter()
+            .filter(|call| call.starts_with("fd_prestat"))
+            .count()
+    };
+    // Looked up on the first call only: the name of fd 3 isn't read, as no known name has
+    // its length
+    for _ in 0..3 {
+        assert_eq!(instance.call("open", (3, BUF)).unwrap(), __WASI_ERRNO_BADF);
+        assert_eq!(instance.call("open", (4, BUF)).unwrap(), 0);
+    }
+    assert_eq!(lookups(&instance), 3);
+    // and again after the fd is closed
+    instance.call("close", (4, 0)).unwrap();
+    assert_eq!(instance.call("open", (4, BUF)).unwrap(), 0);
+    assert_eq!(instance.call("open", (3, BUF)).unwrap(), __WASI_ERRNO_BADF);
+    assert_eq!(lookups(&instance), 5);
+}
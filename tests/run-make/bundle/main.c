@@ -0,0 +1,22 @@
+#include <stdio.h>
+
+// A toy interpreter echoing the lines of the script given as argv[1]
+int main(int argc, char **argv) {
+  for (int i = 0; i < argc; i++) {
+    printf("argv[%d] = %s\n", i, argv[i]);
+  }
+  if (argc < 2) {
+    return 1;
+  }
+  FILE *script = fopen(argv[1], "r");
+  if (!script) {
+    printf("%s: failed to open\n", argv[1]);
+    return 1;
+  }
+  char line[256];
+  while (fgets(line, sizeof(line), script)) {
+    printf("> %s", line);
+  }
+  fclose(script);
+  return 0;
+}
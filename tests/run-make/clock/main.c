@@ -0,0 +1,13 @@
+#include <stdio.h>
+#include <time.h>
+
+int main(int argc, char **argv) {
+  struct timespec ts;
+  for (int i = 0; i < 3; i++) {
+    clock_gettime(CLOCK_REALTIME, &ts);
+    printf("realtime: %lld.%09ld\n", (long long)ts.tv_sec, ts.tv_nsec);
+  }
+  clock_getres(CLOCK_REALTIME, &ts);
+  printf("resolution: %lld.%09ld\n", (long long)ts.tv_sec, ts.tv_nsec);
+  return 0;
+}
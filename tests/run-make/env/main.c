@@ -0,0 +1,12 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+extern char **environ;
+
+int main(int argc, char **argv) {
+  for (char **env = environ; *env; env++) {
+    printf("env: %s\n", *env);
+  }
+  printf("getenv(\"HOME\") = %s\n", getenv("HOME"));
+  return 0;
+}
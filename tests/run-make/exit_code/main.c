@@ -0,0 +1,8 @@
+#include <stdlib.h>
+
+int main(int argc, char **argv) {
+  if (argc > 2) {
+    exit(atoi(argv[2]));
+  }
+  return argc > 1 ? atoi(argv[1]) : 0;
+}
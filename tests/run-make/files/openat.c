@@ -0,0 +1,27 @@
+#include <fcntl.h>
+#include <stdio.h>
+#include <unistd.h>
+
+// Open a directory of the runtime, which gets the first fd free for the runtime, and
+// a file relative to it.
+int main(int argc, char **argv) {
+  if (argc < 3) {
+    return 1;
+  }
+  int dir = open(argv[1], O_RDONLY | O_DIRECTORY);
+  if (dir < 0) {
+    printf("%s: failed to open\n", argv[1]);
+    return 1;
+  }
+  int fd = openat(dir, argv[2], O_RDONLY);
+  if (fd < 0) {
+    printf("%s/%s: failed to open\n", argv[1], argv[2]);
+    return 1;
+  }
+  char buf[256];
+  ssize_t n = read(fd, buf, sizeof(buf));
+  printf("%s/%s: %.*s", argv[1], argv[2], (int)(n < 0 ? 0 : n), buf);
+  close(fd);
+  close(dir);
+  return 0;
+}
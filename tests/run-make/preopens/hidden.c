@@ -0,0 +1,11 @@
+#include <fcntl.h>
+#include <stdio.h>
+#include <sys/stat.h>
+
+// Access argv[1] in the directory preopened at fd 3 without looking up the preopens.
+int main(int argc, char **argv) {
+  struct stat st;
+  printf("openat: %s\n", openat(3, argv[1], O_RDONLY) < 0 ? "failed" : "ok");
+  printf("fstatat: %s\n", fstatat(3, argv[1], &st, 0) < 0 ? "failed" : "ok");
+  return 0;
+}
@@ -0,0 +1,17 @@
+#include <stdio.h>
+
+int main(int argc, char **argv) {
+  for (int i = 1; i < argc; i++) {
+    FILE *file = fopen(argv[i], "r");
+    if (!file) {
+      printf("%s: failed to open\n", argv[i]);
+      continue;
+    }
+    char line[256];
+    while (fgets(line, sizeof(line), file)) {
+      printf("%s: %s", argv[i], line);
+    }
+    fclose(file);
+  }
+  return 0;
+}
@@ -0,0 +1,15 @@
+#include <stdio.h>
+#include <unistd.h>
+
+int main(int argc, char **argv) {
+  unsigned char buf[16];
+  if (getentropy(buf, sizeof(buf)) != 0) {
+    printf("getentropy failed\n");
+    return 1;
+  }
+  for (size_t i = 0; i < sizeof(buf); i++) {
+    printf("%02x", buf[i]);
+  }
+  printf("\n");
+  return 0;
+}
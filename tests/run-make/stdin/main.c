@@ -0,0 +1,9 @@
+#include <stdio.h>
+
+int main(int argc, char **argv) {
+  char line[256];
+  while (fgets(line, sizeof(line), stdin)) {
+    printf("stdin: %s", line);
+  }
+  return 0;
+}